@@ -0,0 +1,478 @@
+//! k-anonymity and l-diversity of tabular datasets.
+//!
+//! Rows are grouped into equivalence classes by their values in the
+//! quasi-identifier columns, i.e. columns that could be linked to outside
+//! data such as age, zip code or sex. A dataset is k-anonymous if every class
+//! has at least k rows, and l-diverse if every class has at least l distinct
+//! values in each sensitive column.
+//!
+//! [anonymize] rewrites a dataset until targets for k and l are met by
+//! generalizing quasi-identifiers step by step (numbers into bands, zip codes
+//! by masking trailing digits, rare categories into "Other" and finally any
+//! value into "*"), and by suppressing the rows of a few remaining small
+//! classes.
+
+use crate::csv::{Table, Writer};
+use crate::profile::{detect_data_type, is_missing};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// Share of rows that may be suppressed instead of generalizing further.
+const MAX_SUPPRESSED_SHARE: f64 = 0.05;
+/// Numeric quasi-identifiers are generalized into bands spanning about this
+/// many parts of their range, one level after the other.
+const BAND_COUNTS: [f64; 4] = [20.0, 10.0, 5.0, 2.0];
+const OTHER: &str = "Other";
+const SUPPRESSED: &str = "*";
+
+/// Columns of a dataset declared by its owner.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnonymityConfig {
+    pub quasi_identifiers: Vec<String>,
+    pub sensitive_columns: Vec<String>,
+}
+
+impl Storable for AnonymityConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnonymityReport {
+    pub config: AnonymityConfig,
+    /// Size of the smallest equivalence class; 0 for an empty dataset.
+    pub k: u32,
+    /// Smallest number of distinct sensitive values in a class, or `None`
+    /// without sensitive columns.
+    pub l: Option<u32>,
+    pub equivalence_classes: u32,
+    pub rows: u32,
+    /// Rows removed by [anonymize].
+    pub suppressed_rows: u32,
+    /// Generalizations applied by [anonymize].
+    pub generalizations: Vec<Generalization>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct Generalization {
+    pub column: String,
+    pub method: String,
+}
+
+/// Measures k and l of `table`.
+pub fn measure(table: &Table, config: &AnonymityConfig) -> Result<AnonymityReport, String> {
+    let columns = Columns::resolve(table, config)?;
+    let classes = equivalence_classes(&table.rows, &columns);
+    Ok(report(table.rows.len(), &classes, config, &columns))
+}
+
+/// Generalizes and suppresses the quasi-identifiers of `table` until it is
+/// `target_k`-anonymous and `target_l`-diverse. Returns the rewritten dataset
+/// as delimited text with a report on it.
+pub fn anonymize(
+    table: &Table,
+    config: &AnonymityConfig,
+    target_k: u32,
+    target_l: u32,
+) -> Result<(String, AnonymityReport), String> {
+    let columns = Columns::resolve(table, config)?;
+    let hierarchies: Vec<Hierarchy> = columns
+        .quasi_identifiers
+        .iter()
+        .map(|&idx| Hierarchy::fit(&table.headers[idx], table, idx, target_k as usize))
+        .collect();
+    let target_k = target_k.max(1) as usize;
+    let target_l = target_l.max(1) as usize;
+    let suppression_limit = (table.rows.len() as f64 * MAX_SUPPRESSED_SHARE) as usize;
+
+    let mut levels = vec![0; hierarchies.len()];
+    loop {
+        let rows: Vec<Vec<String>> = table
+            .rows
+            .iter()
+            .map(|row| {
+                let mut row: Vec<String> = row.iter().map(|v| v.trim().to_string()).collect();
+                for ((&idx, hierarchy), &level) in columns
+                    .quasi_identifiers
+                    .iter()
+                    .zip(&hierarchies)
+                    .zip(&levels)
+                {
+                    row[idx] = hierarchy.generalize(&row[idx], level);
+                }
+                row
+            })
+            .collect();
+        let classes = equivalence_classes(&rows, &columns);
+        let violating: HashSet<usize> = classes
+            .iter()
+            .filter(|class| {
+                class.rows.len() < target_k || class.diversity.iter().any(|&l| l < target_l)
+            })
+            .flat_map(|class| class.rows.iter().copied())
+            .collect();
+
+        // Generalize the column with the most distinct values next.
+        let next = (0..hierarchies.len())
+            .filter(|&c| levels[c] < hierarchies[c].max_level())
+            .max_by_key(|&c| {
+                let idx = columns.quasi_identifiers[c];
+                rows.iter()
+                    .map(|row| &row[idx])
+                    .collect::<HashSet<_>>()
+                    .len()
+            });
+        match next {
+            Some(c) if violating.len() > suppression_limit => levels[c] += 1,
+            _ => {
+                if violating.len() == rows.len() {
+                    return Err(format!(
+                        "the dataset cannot be made {target_k}-anonymous and {target_l}-diverse"
+                    ));
+                }
+                let kept: Vec<Vec<String>> = rows
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| !violating.contains(i))
+                    .map(|(_, row)| row)
+                    .collect();
+                let mut writer = Writer::new(table.delimiter);
+                writer.write_record(&table.headers);
+                for row in &kept {
+                    writer.write_record(row);
+                }
+                let classes = equivalence_classes(&kept, &columns);
+                let mut report = report(kept.len(), &classes, config, &columns);
+                report.suppressed_rows = violating.len() as u32;
+                report.generalizations = columns
+                    .quasi_identifiers
+                    .iter()
+                    .zip(&hierarchies)
+                    .zip(&levels)
+                    .filter(|(_, &level)| level > 0)
+                    .map(|((&idx, hierarchy), &level)| Generalization {
+                        column: table.headers[idx].clone(),
+                        method: hierarchy.describe(level),
+                    })
+                    .collect();
+                return Ok((writer.finish(), report));
+            }
+        }
+    }
+}
+
+/// Indices of the configured columns.
+struct Columns {
+    quasi_identifiers: Vec<usize>,
+    sensitive: Vec<usize>,
+}
+
+impl Columns {
+    fn resolve(table: &Table, config: &AnonymityConfig) -> Result<Self, String> {
+        if config.quasi_identifiers.is_empty() {
+            return Err("no quasi-identifier columns declared".to_string());
+        }
+        let index = |name: &String| {
+            table
+                .headers
+                .iter()
+                .position(|header| header == name)
+                .ok_or_else(|| format!("unknown column {name:?}"))
+        };
+        Ok(Self {
+            quasi_identifiers: config
+                .quasi_identifiers
+                .iter()
+                .map(index)
+                .collect::<Result<_, _>>()?,
+            sensitive: config
+                .sensitive_columns
+                .iter()
+                .map(index)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+struct EquivalenceClass {
+    rows: Vec<usize>,
+    /// Number of distinct values in each sensitive column.
+    diversity: Vec<usize>,
+}
+
+fn equivalence_classes<S: AsRef<str>>(rows: &[Vec<S>], columns: &Columns) -> Vec<EquivalenceClass> {
+    let mut groups: HashMap<Vec<&str>, Vec<usize>> = HashMap::new();
+    for (i, row) in rows.iter().enumerate() {
+        let key = columns
+            .quasi_identifiers
+            .iter()
+            .map(|&idx| row[idx].as_ref().trim())
+            .collect();
+        groups.entry(key).or_default().push(i);
+    }
+    groups
+        .into_values()
+        .map(|members| EquivalenceClass {
+            diversity: columns
+                .sensitive
+                .iter()
+                .map(|&idx| {
+                    members
+                        .iter()
+                        .map(|&i| rows[i][idx].as_ref().trim())
+                        .collect::<HashSet<_>>()
+                        .len()
+                })
+                .collect(),
+            rows: members,
+        })
+        .collect()
+}
+
+fn report(
+    rows: usize,
+    classes: &[EquivalenceClass],
+    config: &AnonymityConfig,
+    columns: &Columns,
+) -> AnonymityReport {
+    let k = classes.iter().map(|c| c.rows.len()).min().unwrap_or(0);
+    let l = if columns.sensitive.is_empty() {
+        None
+    } else {
+        Some(
+            classes
+                .iter()
+                .flat_map(|c| c.diversity.iter().copied())
+                .min()
+                .unwrap_or(0) as u32,
+        )
+    };
+    AnonymityReport {
+        config: config.clone(),
+        k: k as u32,
+        l,
+        equivalence_classes: classes.len() as u32,
+        rows: rows as u32,
+        suppressed_rows: 0,
+        generalizations: Vec::new(),
+    }
+}
+
+/// Generalization steps of a quasi-identifier. Level 0 keeps values as they
+/// are and the highest level replaces every value by "*".
+enum Hierarchy {
+    /// Numeric values, in bands of the given widths.
+    Numeric { widths: Vec<f64>, integer: bool },
+    /// Zip codes with up to `digits` trailing digits masked.
+    Zip { digits: usize },
+    /// Categories with fewer than `min_count` rows merged into "Other".
+    Categorical { rare: HashSet<String> },
+}
+
+impl Hierarchy {
+    fn fit(header: &str, table: &Table, idx: usize, min_count: usize) -> Self {
+        let values: Vec<&str> = table
+            .rows
+            .iter()
+            .map(|row| row[idx].trim())
+            .filter(|v| !is_missing(v))
+            .collect();
+        let lower = header.to_lowercase();
+        if ["zip", "postal", "postcode"]
+            .iter()
+            .any(|w| lower.contains(w))
+        {
+            return Hierarchy::Zip {
+                digits: values.iter().map(|v| v.len()).max().unwrap_or(0),
+            };
+        }
+        if detect_data_type(header, &values) == "numeric" {
+            let numbers: Vec<f64> = values.iter().filter_map(|v| v.parse().ok()).collect();
+            let min = numbers.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = numbers.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            if max > min {
+                let mut widths: Vec<f64> = BAND_COUNTS
+                    .iter()
+                    .map(|parts| nice_width((max - min) / parts))
+                    .collect();
+                widths.dedup();
+                return Hierarchy::Numeric {
+                    widths,
+                    integer: numbers.iter().all(|x| x.fract() == 0.0),
+                };
+            }
+        }
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for value in &values {
+            *counts.entry(value).or_default() += 1;
+        }
+        Hierarchy::Categorical {
+            rare: counts
+                .into_iter()
+                .filter(|&(_, count)| count < min_count)
+                .map(|(value, _)| value.to_string())
+                .collect(),
+        }
+    }
+
+    fn max_level(&self) -> usize {
+        match self {
+            Hierarchy::Numeric { widths, .. } => widths.len() + 1,
+            Hierarchy::Zip { digits } => *digits,
+            Hierarchy::Categorical { .. } => 2,
+        }
+    }
+
+    fn generalize(&self, value: &str, level: usize) -> String {
+        if level == 0 || is_missing(value) {
+            return value.to_string();
+        }
+        if level >= self.max_level() {
+            return SUPPRESSED.to_string();
+        }
+        match self {
+            Hierarchy::Numeric { widths, integer } => {
+                let Ok(x) = value.parse::<f64>() else {
+                    return SUPPRESSED.to_string();
+                };
+                let width = widths[level - 1];
+                let low = (x / width).floor() * width;
+                if *integer && width.fract() == 0.0 {
+                    format!("{}-{}", low, low + width - 1.0)
+                } else {
+                    format!("[{}, {})", low, low + width)
+                }
+            }
+            Hierarchy::Zip { .. } => {
+                let keep = value.chars().count().saturating_sub(level);
+                value
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| if i < keep { c } else { '*' })
+                    .collect()
+            }
+            Hierarchy::Categorical { rare } => {
+                if rare.contains(value) {
+                    OTHER.to_string()
+                } else {
+                    value.to_string()
+                }
+            }
+        }
+    }
+
+    fn describe(&self, level: usize) -> String {
+        if level >= self.max_level() {
+            return "suppressed".to_string();
+        }
+        match self {
+            Hierarchy::Numeric { widths, .. } => format!("bands of width {}", widths[level - 1]),
+            Hierarchy::Zip { .. } => format!("last {level} characters masked"),
+            Hierarchy::Categorical { .. } => "rare categories merged".to_string(),
+        }
+    }
+}
+
+/// Smallest width of the form 1, 2 or 5 times a power of ten that is at
+/// least `width`.
+fn nice_width(width: f64) -> f64 {
+    let magnitude = 10f64.powf(width.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|step| step * magnitude)
+        .find(|&w| w >= width)
+        .unwrap_or(10.0 * magnitude)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patients() -> Table {
+        let mut csv = String::from("age,zip,sex,diagnosis\n");
+        let diagnoses = ["flu", "cold", "asthma", "diabetes"];
+        for i in 0..120 {
+            let sex = if i % 3 == 0 { "M" } else { "F" };
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                18 + (i * 7) % 60,
+                10_000 + (i * 37) % 900,
+                sex,
+                diagnoses[i % 4]
+            ));
+        }
+        Table::read(csv.as_bytes())
+    }
+
+    fn config() -> AnonymityConfig {
+        AnonymityConfig {
+            quasi_identifiers: vec!["age".to_string(), "zip".to_string(), "sex".to_string()],
+            sensitive_columns: vec!["diagnosis".to_string()],
+        }
+    }
+
+    #[test]
+    fn measures_k_and_l() {
+        let report = measure(&patients(), &config()).unwrap();
+        assert_eq!(report.k, 1);
+        assert_eq!(report.l, Some(1));
+        assert_eq!(report.rows, 120);
+
+        let sex_only = AnonymityConfig {
+            quasi_identifiers: vec!["sex".to_string()],
+            sensitive_columns: vec![],
+        };
+        let report = measure(&patients(), &sex_only).unwrap();
+        assert_eq!(
+            (report.k, report.l, report.equivalence_classes),
+            (40, None, 2)
+        );
+
+        let unknown = AnonymityConfig {
+            quasi_identifiers: vec!["height".to_string()],
+            sensitive_columns: vec![],
+        };
+        assert!(measure(&patients(), &unknown).is_err());
+    }
+
+    #[test]
+    fn anonymizes_until_targets_are_met() {
+        let (csv, report) = anonymize(&patients(), &config(), 5, 2).unwrap();
+        assert!(report.k >= 5, "k = {}", report.k);
+        assert!(report.l.unwrap() >= 2);
+        assert!(report.suppressed_rows <= 6);
+        assert!(!report.generalizations.is_empty());
+
+        let output = Table::read(csv.as_bytes());
+        assert_eq!(output.rows.len() as u32, report.rows);
+        assert_eq!(measure(&output, &config()).unwrap().k, report.k);
+        assert!(output
+            .rows
+            .iter()
+            .all(|row| row[0].contains('-') || row[0] == "*"));
+    }
+
+    #[test]
+    fn generalizes_values() {
+        let zip = Hierarchy::Zip { digits: 5 };
+        assert_eq!(zip.generalize("10456", 2), "104**");
+        assert_eq!(zip.generalize("10456", 5), "*");
+        let age = Hierarchy::Numeric {
+            widths: vec![5.0, 10.0],
+            integer: true,
+        };
+        assert_eq!(age.generalize("37", 1), "35-39");
+        assert_eq!(age.generalize("37", 2), "30-39");
+        assert_eq!(age.generalize("", 2), "");
+        assert_eq!(nice_width(4.5), 5.0);
+        assert_eq!(nice_width(0.3), 0.5);
+        assert_eq!(nice_width(100.0), 100.0);
+    }
+}
@@ -0,0 +1,437 @@
+//! Reading and writing of delimited dataset text following RFC 4180.
+//!
+//! Fields may be quoted with `"`; quoted fields can contain delimiters, line
+//! breaks and quotes escaped by doubling them (`""`). Records end with LF or
+//! CRLF. Problems in a single record (bad quoting, invalid UTF-8, a wrong
+//! number of fields) are reported per row and do not abort reading.
+
+use candid::{CandidType, Deserialize};
+
+/// Delimiters recognized by [detect_delimiter], in order of preference.
+const CANDIDATE_DELIMITERS: [u8; 4] = *b",;\t|";
+/// Number of records inspected by [detect_delimiter].
+const SNIFF_RECORDS: usize = 20;
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// A record that could not be read.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct RowError {
+    /// 1-based line on which the record starts.
+    pub line: u64,
+    pub message: String,
+}
+
+/// Streaming reader yielding one record at a time from `input`.
+pub struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    line: u64,
+    record_line: u64,
+    delimiter: u8,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(input: &'a [u8], delimiter: u8) -> Self {
+        let input = input.strip_prefix(UTF8_BOM).unwrap_or(input);
+        Self {
+            input,
+            pos: 0,
+            line: 1,
+            record_line: 0,
+            delimiter,
+        }
+    }
+
+    /// Continues reading `input` where a reader that stopped at
+    /// [Reader::position] left off.
+    fn resume(input: &'a [u8], delimiter: u8, (pos, line): (usize, u64)) -> Self {
+        Self {
+            pos,
+            line,
+            ..Self::new(input, delimiter)
+        }
+    }
+
+    /// Offset in `input` (without a byte order mark) and line at which the
+    /// next record is read.
+    fn position(&self) -> (usize, u64) {
+        (self.pos, self.line)
+    }
+
+    /// Returns the 1-based line on which the most recently read record starts.
+    pub fn record_line(&self) -> u64 {
+        self.record_line
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    /// Consumes a line break starting with `byte`, which was already read.
+    fn end_line(&mut self, byte: u8) {
+        if byte == b'\r' && self.peek() == Some(b'\n') {
+            self.pos += 1;
+        }
+        self.line += 1;
+    }
+
+    /// Reads the raw fields of the next record. Returns `None` at the end of
+    /// the input and skips blank lines.
+    fn next_raw(&mut self) -> Option<Result<Vec<Vec<u8>>, String>> {
+        while let Some(byte @ (b'\r' | b'\n')) = self.peek() {
+            self.pos += 1;
+            self.end_line(byte);
+        }
+        self.peek()?;
+
+        self.record_line = self.line;
+        let mut fields = Vec::new();
+        let mut field = Vec::new();
+        let mut in_quotes = false;
+        let mut quoted = false;
+        let mut error = None;
+        loop {
+            let Some(byte) = self.peek() else {
+                if in_quotes {
+                    error = Some("unterminated quoted field".to_string());
+                }
+                fields.push(field);
+                break;
+            };
+            self.pos += 1;
+            if in_quotes {
+                match byte {
+                    b'"' if self.peek() == Some(b'"') => {
+                        self.pos += 1;
+                        field.push(b'"');
+                    }
+                    b'"' => in_quotes = false,
+                    b'\n' => {
+                        self.line += 1;
+                        field.push(byte);
+                    }
+                    _ => field.push(byte),
+                }
+            } else if byte == self.delimiter {
+                fields.push(std::mem::take(&mut field));
+                quoted = false;
+            } else {
+                match byte {
+                    b'"' if field.is_empty() && !quoted => {
+                        in_quotes = true;
+                        quoted = true;
+                    }
+                    b'\r' | b'\n' => {
+                        self.end_line(byte);
+                        fields.push(field);
+                        break;
+                    }
+                    _ if quoted => {
+                        error.get_or_insert_with(|| {
+                            format!(
+                                "unexpected data after closing quote in field {}",
+                                fields.len() + 1
+                            )
+                        });
+                    }
+                    // A quote inside an unquoted field is kept literally.
+                    _ => field.push(byte),
+                }
+            }
+        }
+        Some(error.map_or(Ok(fields), Err))
+    }
+}
+
+impl Iterator for Reader<'_> {
+    type Item = Result<Vec<String>, RowError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.next_raw()?.and_then(|fields| {
+            fields
+                .into_iter()
+                .enumerate()
+                .map(|(i, bytes)| {
+                    String::from_utf8(bytes)
+                        .map_err(|_| format!("invalid UTF-8 in field {}", i + 1))
+                })
+                .collect()
+        });
+        Some(record.map_err(|message| RowError {
+            line: self.record_line,
+            message,
+        }))
+    }
+}
+
+/// Guesses the delimiter of `input`. Each candidate is scored by how many of
+/// the first records have as many fields as the first one (which must have
+/// more than one); ties prefer more fields. Falls back to a comma.
+pub fn detect_delimiter(input: &[u8]) -> u8 {
+    let mut best = (b',', (0, 0));
+    for delimiter in CANDIDATE_DELIMITERS {
+        let mut counts = Reader::new(input, delimiter)
+            .take(SNIFF_RECORDS)
+            .filter_map(Result::ok)
+            .map(|record| record.len());
+        let Some(first) = counts.next() else {
+            continue;
+        };
+        if first < 2 {
+            continue;
+        }
+        let score = (counts.filter(|&count| count == first).count() + 1, first);
+        if score > best.1 {
+            best = (delimiter, score);
+        }
+    }
+    best.0
+}
+
+/// A fully read dataset: a header row followed by records of equal length.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    pub delimiter: u8,
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+    /// Records that were skipped, including those with a wrong number of fields.
+    pub errors: Vec<RowError>,
+}
+
+impl Table {
+    /// Reads `input`, detecting the delimiter and treating the first record as
+    /// the header row. Header names are trimmed; empty ones are replaced by
+    /// `column_<n>` and duplicates get a numeric suffix.
+    pub fn read(input: &[u8]) -> Self {
+        let mut reader = TableReader::new(input);
+        while !reader.read(input, usize::MAX) {}
+        reader.finish()
+    }
+}
+
+/// Reads a [Table] as [Table::read] does, a part of the input at a time.
+pub struct TableReader {
+    table: Table,
+    position: (usize, u64),
+    headers_read: bool,
+}
+
+impl TableReader {
+    /// Starts reading `input`, detecting its delimiter from its first records.
+    pub fn new(input: &[u8]) -> Self {
+        let delimiter = detect_delimiter(input);
+        Self {
+            table: Table {
+                delimiter,
+                ..Table::default()
+            },
+            position: Reader::new(input, delimiter).position(),
+            headers_read: false,
+        }
+    }
+
+    /// Reads records of `input`, the same input as passed to
+    /// [TableReader::new], until at least `max_bytes` more bytes are consumed.
+    /// Returns true once all of `input` is read.
+    pub fn read(&mut self, input: &[u8], max_bytes: usize) -> bool {
+        let table = &mut self.table;
+        let mut reader = Reader::resume(input, table.delimiter, self.position);
+        let end = self.position.0.saturating_add(max_bytes);
+        while reader.position().0 < end {
+            let Some(record) = reader.next() else {
+                self.position = reader.position();
+                return true;
+            };
+            match record {
+                Ok(headers) if !self.headers_read => {
+                    table.headers = unique_headers(headers);
+                    self.headers_read = true;
+                }
+                Ok(row) if row.len() == table.headers.len() => table.rows.push(row),
+                Ok(row) => table.errors.push(RowError {
+                    line: reader.record_line(),
+                    message: format!(
+                        "expected {} fields, found {}",
+                        table.headers.len(),
+                        row.len()
+                    ),
+                }),
+                Err(error) => table.errors.push(error),
+            }
+        }
+        self.position = reader.position();
+        false
+    }
+
+    pub fn finish(self) -> Table {
+        self.table
+    }
+}
+
+fn unique_headers(headers: Vec<String>) -> Vec<String> {
+    let mut result: Vec<String> = Vec::with_capacity(headers.len());
+    for (i, header) in headers.into_iter().enumerate() {
+        let header = header.trim();
+        let base = if header.is_empty() {
+            format!("column_{}", i + 1)
+        } else {
+            header.to_string()
+        };
+        let mut name = base.clone();
+        let mut suffix = 2;
+        while result.contains(&name) {
+            name = format!("{base}_{suffix}");
+            suffix += 1;
+        }
+        result.push(name);
+    }
+    result
+}
+
+/// Builds delimited text, quoting fields only where needed.
+pub struct Writer {
+    delimiter: u8,
+    out: String,
+    /// Whether a record was written, possibly already taken from `out`.
+    written: bool,
+}
+
+impl Writer {
+    pub fn new(delimiter: u8) -> Self {
+        Self {
+            delimiter,
+            out: String::new(),
+            written: false,
+        }
+    }
+
+    pub fn write_record<I, S>(&mut self, fields: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        if self.written {
+            self.out.push('\n');
+        }
+        self.written = true;
+        let fields: Vec<S> = fields.into_iter().collect();
+        // A single empty field would be a blank line, which readers skip.
+        let lone_empty_field = fields.len() == 1 && fields[0].as_ref().is_empty();
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                self.out.push(self.delimiter as char);
+            }
+            let field = field.as_ref();
+            let needs_quotes = lone_empty_field
+                || field
+                    .bytes()
+                    .any(|b| b == self.delimiter || matches!(b, b'"' | b'\r' | b'\n'));
+            if needs_quotes {
+                self.out.push('"');
+                self.out.push_str(&field.replace('"', "\"\""));
+                self.out.push('"');
+            } else {
+                self.out.push_str(field);
+            }
+        }
+    }
+
+    /// Returns the text written since the last call, so that the output can
+    /// be stored a part at a time.
+    pub fn take(&mut self) -> String {
+        std::mem::take(&mut self.out)
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(input: &str, delimiter: u8) -> Vec<Vec<String>> {
+        Reader::new(input.as_bytes(), delimiter)
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn reads_quoted_fields() {
+        let input = "name,note\n\"Doe, Jane\",\"said \"\"hi\"\"\"\n\"multi\nline\",x\n";
+        assert_eq!(
+            records(input, b','),
+            [
+                vec!["name", "note"],
+                vec!["Doe, Jane", "said \"hi\""],
+                vec!["multi\nline", "x"],
+            ]
+        );
+
+        let mut reader = Reader::new(input.as_bytes(), b',');
+        reader.nth(2);
+        assert_eq!(reader.record_line(), 3);
+    }
+
+    #[test]
+    fn reads_crlf_and_skips_bom_and_blank_lines() {
+        let input = "\u{feff}a,b\r\n1,2\r\n\r\n3,4";
+        assert_eq!(
+            records(input, b','),
+            [vec!["a", "b"], vec!["1", "2"], vec!["3", "4"]]
+        );
+    }
+
+    #[test]
+    fn reports_bad_records_per_row() {
+        let table = Table::read(b"a,b\n1,2\n\"x\"y,3\n4\n5,6\n");
+        assert_eq!(table.rows, [vec!["1", "2"], vec!["5", "6"]]);
+        let lines: Vec<u64> = table.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [3, 4]);
+    }
+
+    #[test]
+    fn detects_delimiters() {
+        assert_eq!(detect_delimiter(b"a;b;c\n1;2,5;3\n4;5;6\n"), b';');
+        assert_eq!(detect_delimiter(b"a\tb\n1\t2\n"), b'\t');
+        assert_eq!(detect_delimiter(b"a,b\n1,2\n"), b',');
+        assert_eq!(detect_delimiter(b"single\ncolumn\n"), b',');
+    }
+
+    #[test]
+    fn tables_read_in_parts_equal_tables_read_at_once() {
+        let input = "\u{feff}a,b\n1,\"x\ny\"\n\n2,3,4\r\n5,6\n";
+        let input = input.as_bytes();
+        let mut reader = TableReader::new(input);
+        let mut parts = 1;
+        while !reader.read(input, 1) {
+            parts += 1;
+        }
+        assert!(parts > 3);
+        assert_eq!(reader.finish(), Table::read(input));
+    }
+
+    #[test]
+    fn written_records_read_back_unchanged() {
+        let written = [
+            vec!["id", "text"],
+            vec!["1", "comma, quote \" and\r\nline break"],
+            vec!["2", ""],
+            vec![""],
+            vec!["3", "plain"],
+        ];
+        for delimiter in *b",;\t" {
+            let mut writer = Writer::new(delimiter);
+            let mut text = String::new();
+            for (i, record) in written.iter().enumerate() {
+                writer.write_record(record);
+                if i % 2 == 0 {
+                    text.push_str(&writer.take());
+                }
+            }
+            text.push_str(&writer.finish());
+            assert_eq!(records(&text, delimiter), written, "{text:?}");
+        }
+    }
+}
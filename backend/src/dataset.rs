@@ -0,0 +1,162 @@
+//! Descriptive metadata of datasets, kept next to their content so that
+//! listings can show a dataset without reading or decrypting it.
+
+use crate::csv::Table;
+use crate::profile::{detect_data_type, is_missing};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum DatasetKind {
+    /// Uploaded or derived from an uploaded dataset.
+    Raw,
+    /// Generated by a synthetic job.
+    Synthetic,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct SchemaColumn {
+    pub name: String,
+    /// "id", "numeric", "categorical", "date", "text" or a PII type, as
+    /// detected by profiling.
+    pub data_type: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DatasetMetadata {
+    pub title: String,
+    pub description: String,
+    /// Columns of the content read as CSV; empty if it has no header row.
+    pub schema: Vec<SchemaColumn>,
+    pub byte_size: u64,
+    /// Number of readable records after the header row. `None` for content
+    /// uploaded in chunks, which is not read in full.
+    pub row_count: Option<u64>,
+    pub created_at: u64,
+    /// Last change of the content or of the title and description.
+    pub updated_at: u64,
+    /// Hex-encoded SHA-256 of the content.
+    pub content_hash: String,
+    pub kind: DatasetKind,
+}
+
+impl Storable for DatasetMetadata {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl DatasetMetadata {
+    /// Metadata of an empty, untitled dataset created at `now`.
+    pub fn new(kind: DatasetKind, now: u64) -> Self {
+        let mut metadata = Self {
+            title: String::new(),
+            description: String::new(),
+            schema: Vec::new(),
+            byte_size: 0,
+            row_count: None,
+            created_at: now,
+            updated_at: now,
+            content_hash: String::new(),
+            kind,
+        };
+        metadata.describe_content(&[], now);
+        metadata
+    }
+
+    /// Describes `content`, which replaced the previous content at `now`.
+    pub fn describe_content(&mut self, content: &[u8], now: u64) {
+        let table = Table::read(content);
+        self.schema = schema(&table);
+        self.byte_size = content.len() as u64;
+        self.row_count = Some(table.rows.len() as u64);
+        self.content_hash = hex::encode(Sha256::digest(content));
+        self.updated_at = now;
+    }
+
+    /// Describes content uploaded in chunks from its size, its hash and its
+    /// first chunk, from which the schema is detected.
+    pub fn describe_chunked_content(
+        &mut self,
+        total_bytes: u64,
+        sha256: &str,
+        first_chunk: &[u8],
+        now: u64,
+    ) {
+        self.schema = schema(&Table::read(first_chunk));
+        self.byte_size = total_bytes;
+        self.row_count = None;
+        self.content_hash = sha256.to_lowercase();
+        self.updated_at = now;
+    }
+}
+
+fn schema(table: &Table) -> Vec<SchemaColumn> {
+    table
+        .headers
+        .iter()
+        .enumerate()
+        .map(|(idx, header)| {
+            let values: Vec<&str> = table
+                .rows
+                .iter()
+                .map(|row| row[idx].trim())
+                .filter(|v| !is_missing(v))
+                .collect();
+            SchemaColumn {
+                name: header.clone(),
+                data_type: detect_data_type(header, &values).to_string(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_csv_content() {
+        let mut metadata = DatasetMetadata::new(DatasetKind::Raw, 1);
+        assert_eq!(metadata.byte_size, 0);
+        assert_eq!(metadata.row_count, Some(0));
+        assert!(metadata.schema.is_empty());
+
+        let content = b"patient_id,age,sex\n1,34,F\n2,51,M\n3,29,F\n";
+        metadata.describe_content(content, 5);
+        let types: Vec<(&str, &str)> = metadata
+            .schema
+            .iter()
+            .map(|c| (c.name.as_str(), c.data_type.as_str()))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                ("patient_id", "id"),
+                ("age", "numeric"),
+                ("sex", "categorical")
+            ]
+        );
+        assert_eq!(metadata.row_count, Some(3));
+        assert_eq!(metadata.byte_size, content.len() as u64);
+        assert_eq!(metadata.content_hash, hex::encode(Sha256::digest(content)));
+        assert_eq!((metadata.created_at, metadata.updated_at), (1, 5));
+    }
+
+    #[test]
+    fn describes_chunked_content_from_first_chunk() {
+        let mut metadata = DatasetMetadata::new(DatasetKind::Synthetic, 1);
+        metadata.describe_chunked_content(1 << 30, "ABCD", b"age,sex\n34,F\n51,", 2);
+        assert_eq!(metadata.schema.len(), 2);
+        assert_eq!(metadata.byte_size, 1 << 30);
+        assert_eq!(metadata.row_count, None);
+        assert_eq!(metadata.content_hash, "abcd");
+        assert_eq!(metadata.kind, DatasetKind::Synthetic);
+    }
+}
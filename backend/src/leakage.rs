@@ -0,0 +1,452 @@
+//! Privacy leakage evaluation of synthetic datasets against their source.
+//!
+//! Rows are compared with the Gower distance: the mean over the shared
+//! columns of a per-column distance in `[0, 1]`, which is the absolute
+//! difference divided by the source range for numeric columns and 0 or 1
+//! (equal or not) otherwise. As in [crate::quality], only numeric,
+//! categorical and date columns are compared: synthetic datasets get fresh
+//! identifiers, and masked values such as emails or free text never match
+//! their source, which would hide rows copied in all other columns.
+
+use crate::csv::Table;
+use crate::profile::{detect_data_type, is_missing};
+use candid::{CandidType, Deserialize};
+use std::collections::{HashMap, HashSet};
+
+/// Upper bound on the rows compared from each side in the distance
+/// computations, which bounds their quadratic cost. Exact matches are looked
+/// up for all rows.
+const MAX_EVALUATED_ROWS: usize = 2_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct LeakageReport {
+    /// Share of synthetic rows equal to a source row in all compared columns.
+    pub exact_match_rate: f64,
+    /// Distance to the closest source record (DCR) of synthetic rows.
+    pub dcr_min: f64,
+    pub dcr_5th_percentile: f64,
+    pub dcr_median: f64,
+    /// Share of source rows that are closer to a synthetic row than to any
+    /// other source row. About 0.5 for as many synthetic rows as if drawn
+    /// independently from the source distribution, and close to 1 if the
+    /// generator memorizes its input.
+    pub membership_risk: f64,
+    /// Rows sampled for the distances and the membership risk.
+    pub evaluated_source_rows: u32,
+    pub evaluated_synthetic_rows: u32,
+}
+
+/// Limits a synthetic dataset must stay within; unset limits are not checked.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct LeakageThresholds {
+    pub max_exact_match_rate: Option<f64>,
+    pub min_dcr_5th_percentile: Option<f64>,
+    pub max_membership_risk: Option<f64>,
+}
+
+impl LeakageThresholds {
+    /// Describes the thresholds `report` exceeds.
+    pub fn violations(&self, report: &LeakageReport) -> Vec<String> {
+        let mut violations = Vec::new();
+        if let Some(max) = self.max_exact_match_rate {
+            if report.exact_match_rate > max {
+                violations.push(format!(
+                    "exact match rate {:.4} exceeds {}",
+                    report.exact_match_rate, max
+                ));
+            }
+        }
+        if let Some(min) = self.min_dcr_5th_percentile {
+            if report.dcr_5th_percentile < min {
+                violations.push(format!(
+                    "5th percentile of the distance to closest record {:.4} is below {}",
+                    report.dcr_5th_percentile, min
+                ));
+            }
+        }
+        if let Some(max) = self.max_membership_risk {
+            if report.membership_risk > max {
+                violations.push(format!(
+                    "membership inference risk {:.4} exceeds {}",
+                    report.membership_risk, max
+                ));
+            }
+        }
+        violations
+    }
+}
+
+/// Evaluates how closely `synthetic` reproduces rows of `source`.
+#[cfg(test)]
+pub fn evaluate(source: &Table, synthetic: &Table) -> LeakageReport {
+    let mut evaluation = Evaluation::default();
+    while !evaluation.step(source, synthetic) {}
+    evaluation.finish()
+}
+
+/// An [evaluate] done a bounded amount of work per [Evaluation::step]: one
+/// column, [ROWS_PER_STEP] rows, or the distances of one sampled row.
+#[derive(Default)]
+pub struct Evaluation {
+    encoder: Encoder,
+    stage: Stage,
+    /// Next column, batch of [ROWS_PER_STEP] rows or sampled row of the
+    /// current stage.
+    next: usize,
+    source_keys: HashSet<Vec<Cell>>,
+    source_sample: Vec<Vec<Cell>>,
+    synthetic_sample: Vec<Vec<Cell>>,
+    synthetic_rows: usize,
+    exact_matches: usize,
+    dcr: Vec<f64>,
+    members_at_risk: usize,
+}
+
+/// Rows encoded per [Evaluation::step].
+const ROWS_PER_STEP: usize = 1_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Stage {
+    /// Choosing the compared columns.
+    #[default]
+    Columns,
+    EncodingSource,
+    /// Encoding synthetic rows and looking them up among the source rows.
+    EncodingSynthetic,
+    /// Distances to the closest source record of sampled synthetic rows.
+    Distances,
+    /// Whether sampled source rows are closer to a synthetic row than to
+    /// other source rows.
+    Membership,
+    Done,
+}
+
+impl Evaluation {
+    /// Does the next part of the evaluation of `source` and `synthetic`, the
+    /// same tables in every call. Returns true once it is complete.
+    pub fn step(&mut self, source: &Table, synthetic: &Table) -> bool {
+        let (stage, next) = (self.stage, self.next);
+        self.next += 1;
+        match stage {
+            Stage::Columns if next < source.headers.len() => {
+                self.encoder.add_column(source, synthetic, next);
+            }
+            Stage::EncodingSource | Stage::EncodingSynthetic => {
+                let (table, columns) = match stage {
+                    Stage::EncodingSource => (source, self.encoder.source_columns()),
+                    _ => (synthetic, self.encoder.synthetic_columns()),
+                };
+                let step = sample_step(table.rows.len());
+                let start = next * ROWS_PER_STEP;
+                let end = (start + ROWS_PER_STEP).min(table.rows.len());
+                for idx in start..end {
+                    let row = self.encoder.encode(&table.rows[idx], &columns);
+                    let sampled = idx % step == 0;
+                    if stage == Stage::EncodingSource {
+                        if sampled {
+                            self.source_sample.push(row.clone());
+                        }
+                        self.source_keys.insert(row);
+                    } else {
+                        self.synthetic_rows += 1;
+                        if self.source_keys.contains(&row) {
+                            self.exact_matches += 1;
+                        }
+                        if sampled {
+                            self.synthetic_sample.push(row);
+                        }
+                    }
+                }
+                if end < table.rows.len() {
+                    return false;
+                }
+            }
+            Stage::Distances if next < self.synthetic_sample.len() => {
+                let row = &self.synthetic_sample[next];
+                let distance = closest(row, self.source_sample.iter(), &self.encoder.ranges);
+                self.dcr.push(distance);
+            }
+            Stage::Membership if next < self.source_sample.len() => {
+                let row = &self.source_sample[next];
+                let ranges = &self.encoder.ranges;
+                let to_synthetic = closest(row, self.synthetic_sample.iter(), ranges);
+                let others = self
+                    .source_sample
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, _)| j != next)
+                    .map(|(_, other)| other);
+                if to_synthetic < closest(row, others, ranges) {
+                    self.members_at_risk += 1;
+                }
+            }
+            Stage::Done => return true,
+            _ => {}
+        }
+        let finished = match stage {
+            Stage::Columns => next + 1 >= source.headers.len(),
+            Stage::EncodingSource | Stage::EncodingSynthetic => true,
+            Stage::Distances => next + 1 >= self.synthetic_sample.len(),
+            Stage::Membership => next + 1 >= self.source_sample.len(),
+            Stage::Done => true,
+        };
+        if finished {
+            self.stage = match stage {
+                Stage::Columns => Stage::EncodingSource,
+                Stage::EncodingSource => Stage::EncodingSynthetic,
+                Stage::EncodingSynthetic => Stage::Distances,
+                Stage::Distances => Stage::Membership,
+                Stage::Membership | Stage::Done => Stage::Done,
+            };
+            self.next = 0;
+        }
+        self.stage == Stage::Done
+    }
+
+    pub fn finish(mut self) -> LeakageReport {
+        self.dcr.sort_by(f64::total_cmp);
+        LeakageReport {
+            exact_match_rate: rate(self.exact_matches, self.synthetic_rows),
+            dcr_min: percentile(&self.dcr, 0.0),
+            dcr_5th_percentile: percentile(&self.dcr, 0.05),
+            dcr_median: percentile(&self.dcr, 0.5),
+            membership_risk: rate(self.members_at_risk, self.source_sample.len()),
+            evaluated_source_rows: self.source_sample.len() as u32,
+            evaluated_synthetic_rows: self.synthetic_sample.len() as u32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Cell {
+    Missing,
+    /// Bit pattern of a number, so that cells can be hashed.
+    Number(u64),
+    /// Interned text.
+    Text(u32),
+}
+
+/// Maps rows of both datasets to comparable cells.
+#[derive(Default)]
+struct Encoder {
+    /// Column pairs (source index, synthetic index) that are compared.
+    columns: Vec<(usize, usize)>,
+    /// Source range of each compared numeric column, `None` for text.
+    ranges: Vec<Option<f64>>,
+    texts: HashMap<String, u32>,
+}
+
+impl Encoder {
+    /// Compares source column `idx` if it is in `synthetic` and of a type
+    /// that is expected to match.
+    fn add_column(&mut self, source: &Table, synthetic: &Table, idx: usize) {
+        let header = &source.headers[idx];
+        let Some(synthetic_idx) = synthetic.headers.iter().position(|h| h == header) else {
+            return;
+        };
+        let values: Vec<&str> = source
+            .rows
+            .iter()
+            .map(|row| row[idx].trim())
+            .filter(|v| !is_missing(v))
+            .collect();
+        let range = match detect_data_type(header, &values) {
+            "numeric" => {
+                let numbers = values.iter().filter_map(|v| v.parse::<f64>().ok());
+                let (min, max) = numbers.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), x| {
+                    (lo.min(x), hi.max(x))
+                });
+                Some(if max > min { max - min } else { 1.0 })
+            }
+            "categorical" | "date" => None,
+            // Identifiers and masked values are not expected to match.
+            _ => return,
+        };
+        self.columns.push((idx, synthetic_idx));
+        self.ranges.push(range);
+    }
+
+    fn source_columns(&self) -> Vec<usize> {
+        self.columns.iter().map(|c| c.0).collect()
+    }
+
+    fn synthetic_columns(&self) -> Vec<usize> {
+        self.columns.iter().map(|c| c.1).collect()
+    }
+
+    fn encode(&mut self, row: &[String], columns: &[usize]) -> Vec<Cell> {
+        let texts = &mut self.texts;
+        columns
+            .iter()
+            .zip(&self.ranges)
+            .map(|(&idx, range)| {
+                let value = row[idx].trim();
+                if is_missing(value) {
+                    return Cell::Missing;
+                }
+                if range.is_some() {
+                    if let Ok(x) = value.parse::<f64>() {
+                        return Cell::Number(x.to_bits());
+                    }
+                }
+                let next = texts.len() as u32;
+                Cell::Text(*texts.entry(value.to_string()).or_insert(next))
+            })
+            .collect()
+    }
+}
+
+/// Every how many rows of `rows` rows are sampled, so that at most
+/// [MAX_EVALUATED_ROWS] are.
+fn sample_step(rows: usize) -> usize {
+    rows.div_ceil(MAX_EVALUATED_ROWS).max(1)
+}
+
+fn distance(a: &[Cell], b: &[Cell], ranges: &[Option<f64>]) -> f64 {
+    if ranges.is_empty() {
+        return 0.0;
+    }
+    let total: f64 = a
+        .iter()
+        .zip(b)
+        .zip(ranges)
+        .map(|((a, b), range)| match (a, b, range) {
+            (Cell::Number(x), Cell::Number(y), Some(range)) => {
+                ((f64::from_bits(*x) - f64::from_bits(*y)).abs() / range).min(1.0)
+            }
+            _ if a == b => 0.0,
+            _ => 1.0,
+        })
+        .sum();
+    total / ranges.len() as f64
+}
+
+/// Distance from `row` to the closest of `others`, or 1 if there are none.
+fn closest<'a>(
+    row: &[Cell],
+    others: impl Iterator<Item = &'a Vec<Cell>>,
+    ranges: &[Option<f64>],
+) -> f64 {
+    others
+        .map(|other| distance(row, other, ranges))
+        .fold(1.0, f64::min)
+}
+
+/// Value at quantile `q` of ascending `values`; 0 if there are none.
+fn percentile(values: &[f64], q: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values[((values.len() - 1) as f64 * q).round() as usize]
+}
+
+fn rate(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use crate::synth::{FitOptions, TableModel};
+
+    fn source() -> Table {
+        let mut csv = String::from("id,age,weight,sex,diagnosis\n");
+        let diagnoses = ["flu", "cold", "asthma"];
+        let mut rng = Rng::seed_from_u64(4);
+        for i in 0..300 {
+            csv.push_str(&format!(
+                "{},{},{:.1},{},{}\n",
+                i,
+                20 + rng.below(60),
+                70.0 + 12.0 * rng.normal(),
+                if rng.chance(0.5) { "M" } else { "F" },
+                diagnoses[rng.below(3)]
+            ));
+        }
+        Table::read(csv.as_bytes())
+    }
+
+    #[test]
+    fn copied_rows_are_detected() {
+        let source = source();
+        let report = evaluate(&source, &source);
+        assert_eq!(report.exact_match_rate, 1.0);
+        assert_eq!(report.dcr_median, 0.0);
+        assert_eq!(report.membership_risk, 1.0);
+        assert_eq!(report.evaluated_source_rows, 300);
+
+        let thresholds = LeakageThresholds {
+            max_exact_match_rate: Some(0.1),
+            min_dcr_5th_percentile: None,
+            max_membership_risk: None,
+        };
+        assert_eq!(thresholds.violations(&report).len(), 1);
+    }
+
+    #[test]
+    fn generated_rows_are_further_away_than_copies() {
+        let source = source();
+        let options = FitOptions {
+            mask_pii: true,
+            preserve_correlations: false,
+            epsilon: Some(1.0),
+        };
+        let mut rng = Rng::seed_from_u64(9);
+        let csv = TableModel::fit(&source, &options, &mut rng).generate(300, &mut rng);
+        let report = evaluate(&source, &Table::read(csv.as_bytes()));
+        assert!(report.exact_match_rate < 0.05);
+        assert!(report.dcr_median > 0.0);
+        assert!(
+            report.membership_risk < 0.7,
+            "risk {}",
+            report.membership_risk
+        );
+    }
+
+    #[test]
+    fn masked_columns_do_not_hide_copied_rows() {
+        let source = source();
+        let mut csv = String::from("id,age,weight,sex,diagnosis,email\n");
+        for (i, row) in source.rows.iter().enumerate() {
+            csv.push_str(&format!("{},u{}@example.org\n", row.join(","), i));
+        }
+        let source = Table::read(csv.as_bytes());
+        let mut masked = source.clone();
+        for (i, row) in masked.rows.iter_mut().enumerate() {
+            row[0] = (i + 1_000).to_string();
+            row[5] = format!("x{}@example.com", i * 7);
+        }
+        let report = evaluate(&source, &masked);
+        assert_eq!(report.exact_match_rate, 1.0);
+        assert_eq!(report.dcr_median, 0.0);
+    }
+
+    #[test]
+    fn copies_of_rows_left_out_of_the_sample_are_detected() {
+        let mut csv = String::from("id,weight\n");
+        for i in 0..3 * MAX_EVALUATED_ROWS {
+            csv.push_str(&format!("{},{:.2}\n", i, 50.0 + i as f64 / 100.0));
+        }
+        let source = Table::read(csv.as_bytes());
+        let mut copies = source.clone();
+        // Every third row is sampled, so none of these copies is.
+        copies.rows = source.rows.iter().skip(1).step_by(3).cloned().collect();
+        let report = evaluate(&source, &copies);
+        assert_eq!(report.exact_match_rate, 1.0);
+        assert_eq!(report.evaluated_source_rows as usize, MAX_EVALUATED_ROWS);
+    }
+
+    #[test]
+    fn gower_distance_normalizes_numbers() {
+        let ranges = [Some(10.0), None];
+        let a = [Cell::Number(5f64.to_bits()), Cell::Text(0)];
+        let b = [Cell::Number(10f64.to_bits()), Cell::Text(1)];
+        assert_eq!(distance(&a, &b, &ranges), 0.75);
+        assert_eq!(distance(&a, &a, &ranges), 0.0);
+    }
+}
@@ -1,11 +1,37 @@
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
-use ic_cdk::update;
+use ic_cdk::management_canister::{
+    VetKDCurve, VetKDDeriveKeyArgs, VetKDDeriveKeyResult, VetKDKeyId, VetKDPublicKeyArgs,
+    VetKDPublicKeyResult,
+};
+use ic_cdk::{post_upgrade, update};
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{
     storable::Bound, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+mod anonymity;
+mod csv;
+mod dataset;
+mod leakage;
+mod profile;
+mod quality;
+mod rng;
+mod sha256;
+mod synth;
+mod system;
+
+use anonymity::{AnonymityConfig, AnonymityReport};
+use dataset::{DatasetKind, DatasetMetadata};
+use leakage::{LeakageReport, LeakageThresholds};
+use profile::DataProfile;
+use quality::QualityReport;
+use sha256::Sha256State;
 
 type PrincipalName = String;
 type Memory = VirtualMemory<DefaultMemoryImpl>;
@@ -13,34 +39,241 @@ type NoteId = u128;
 type PassportId = u128;
 type AgentMemoryId = u128;
 type ApiTokenId = u128;
-type SyntheticJobId = String;
+type SyntheticJobId = u128;
+type UploadId = u128;
+type VersionNumber = u32;
+
+/// Error returned by the public methods of this canister.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum Error {
+    /// The referenced note, passport, job or upload does not exist.
+    NotFound(String),
+    /// The caller is anonymous or lacks access to the referenced item.
+    Unauthorized(String),
+    /// A per-user, per-note or canister-wide limit would be exceeded.
+    QuotaExceeded(String),
+    /// The arguments are malformed or inconsistent with the current state.
+    InvalidInput(String),
+    /// A text argument that should name a principal is not a valid principal.
+    InvalidPrincipal(String),
+    /// A call to another canister failed or the canister state is inconsistent.
+    Internal(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotFound(message)
+            | Error::Unauthorized(message)
+            | Error::QuotaExceeded(message)
+            | Error::InvalidInput(message)
+            | Error::InvalidPrincipal(message)
+            | Error::Internal(message) => f.write_str(message),
+        }
+    }
+}
 
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct EncryptedNote {
     id: NoteId,
     encrypted_text: String,
     owner: PrincipalName,
-    /// Principals with whom this note is shared. Does not include the owner.
+    /// Principals with whom this note is shared and their roles. Does not include the owner.
     /// Needed to be able to efficiently show in the UI with whom this note is shared.
+    users: Vec<NoteShare>,
+    /// Set if the content was uploaded in chunks. In that case `encrypted_text` is
+    /// empty and the content lives in [CONTENT_CHUNKS].
+    chunked_content: Option<ChunkedContent>,
+}
+
+/// [EncryptedNote] as stored while shares had no roles.
+#[derive(CandidType, Deserialize)]
+struct LegacyEncryptedNote {
+    id: NoteId,
+    encrypted_text: String,
+    owner: PrincipalName,
     users: Vec<PrincipalName>,
+    chunked_content: Option<ChunkedContent>,
+}
+
+impl From<LegacyEncryptedNote> for EncryptedNote {
+    fn from(note: LegacyEncryptedNote) -> Self {
+        Self {
+            id: note.id,
+            encrypted_text: note.encrypted_text,
+            owner: note.owner,
+            // Shares used to grant full read and write access.
+            users: note
+                .users
+                .into_iter()
+                .map(|user| NoteShare {
+                    user,
+                    role: ShareRole::Editor,
+                    expires_at: None,
+                })
+                .collect(),
+            chunked_content: note.chunked_content,
+        }
+    }
+}
+
+/// Access granted by sharing a note, in increasing order.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ShareRole {
+    /// Can read the note and derive its key.
+    Viewer,
+    /// Can also change the content and metadata and start synthetic jobs.
+    Editor,
+    /// Can also share the note and change the roles of its users.
+    CoOwner,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct NoteShare {
+    user: PrincipalName,
+    role: ShareRole,
+    /// Time in nanoseconds after which the share no longer grants access
+    /// and is removed; `None` for shares that do not expire.
+    expires_at: Option<u64>,
+}
+
+impl NoteShare {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Location and integrity information of note content stored in chunks.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ChunkedContent {
+    upload_id: UploadId,
+    chunk_count: u32,
+    total_bytes: u64,
+    /// Hex-encoded SHA-256 of the concatenated chunks.
+    sha256: String,
+}
+
+/// An immutable snapshot of the content of a note. Versions are numbered
+/// from 1 in the order they were created.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct NoteVersion {
+    note_id: NoteId,
+    version: VersionNumber,
+    /// Hex-encoded SHA-256 of the content.
+    content_hash: String,
+    byte_size: u64,
+    /// Principal whose change created this version.
+    author: PrincipalName,
+    created_at: u64,
+    /// Only returned by [get_note_version]. Stored in [VERSION_TEXTS], except
+    /// for the latest version, whose text is the note's content.
+    encrypted_text: Option<String>,
+    /// Set if the content was uploaded in chunks, which are kept as long as
+    /// a version refers to them.
+    chunked_content: Option<ChunkedContent>,
+}
+
+impl Storable for NoteVersion {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 impl EncryptedNote {
     pub fn is_authorized(&self, user: &PrincipalName) -> bool {
-        user == &self.owner || self.users.contains(user)
+        self.has_role(user, ShareRole::Viewer)
+    }
+
+    /// Whether `user` owns the note or it is shared with them with at least
+    /// role `role`.
+    pub fn has_role(&self, user: &PrincipalName, role: ShareRole) -> bool {
+        user == &self.owner || self.role_of(user).is_some_and(|granted| granted >= role)
+    }
+
+    fn role_of(&self, user: &PrincipalName) -> Option<ShareRole> {
+        let now = system::time();
+        self.users
+            .iter()
+            .find(|share| &share.user == user && !share.is_expired(now))
+            .map(|share| share.role)
     }
 }
 
 impl Storable for EncryptedNote {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyEncryptedNote).unwrap().into())
     }
     const BOUND: Bound = Bound::Unbounded;
 }
 
+/// A note as listed by [get_notes]: its content is left out unless requested.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct NoteListing {
+    id: NoteId,
+    owner: PrincipalName,
+    users: Vec<NoteShare>,
+    chunked_content: Option<ChunkedContent>,
+    encrypted_text: Option<String>,
+    /// Set instead of `encrypted_text` if the content was requested but does
+    /// not fit into a page on its own: the number of chunks to read it with
+    /// [get_note_chunk].
+    omitted_content_chunks: Option<u32>,
+    /// `None` for notes whose content has not changed since metadata was
+    /// introduced and that have not been described by [get_dataset_metadata]
+    /// or [set_dataset_metadata].
+    metadata: Option<DatasetMetadata>,
+}
+
+impl NoteListing {
+    fn new(note: EncryptedNote, include_content: bool) -> Self {
+        let metadata = DATASET_METADATA.with_borrow(|metadata| metadata.get(&note.id));
+        let chunks = text_chunk_count(&note.encrypted_text);
+        let mut listing = Self {
+            id: note.id,
+            owner: note.owner,
+            users: note.users,
+            chunked_content: note.chunked_content,
+            encrypted_text: include_content.then_some(note.encrypted_text),
+            omitted_content_chunks: None,
+            metadata,
+        };
+        if include_content && Encode!(&listing).map_or(0, |bytes| bytes.len()) > MAX_PAGE_BYTES {
+            listing.encrypted_text = None;
+            listing.omitted_content_chunks = Some(chunks);
+        }
+        listing
+    }
+}
+
+/// Position in a listing after which the next page starts. Clients should
+/// treat it as opaque and pass it back unchanged.
+type Cursor = String;
+
+/// Selects a page of a listing: up to `limit` items (default
+/// [DEFAULT_PAGE_SIZE], at most [MAX_PAGE_SIZE]) after `cursor`, or from the
+/// start if it is absent.
+#[derive(Clone, Debug, CandidType, Deserialize, Default, Eq, PartialEq)]
+pub struct PageRequest {
+    pub cursor: Option<Cursor>,
+    pub limit: Option<u32>,
+}
+
+/// Items of a listing in ascending ID order. `next_cursor` is set if more
+/// items may follow.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct AgentPassport {
     id: PassportId,
@@ -62,7 +295,7 @@ impl AgentPassport {
 }
 
 impl Storable for AgentPassport {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -89,7 +322,7 @@ impl AgentMemory {
 }
 
 impl Storable for AgentMemory {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -123,7 +356,7 @@ impl ApiToken {
         }
         
         if let Some(expires_at) = self.expires_at {
-            let current_time = ic_cdk::api::time();
+            let current_time = system::time();
             if current_time > expires_at {
                 return false;
             }
@@ -139,7 +372,7 @@ impl ApiToken {
 }
 
 impl Storable for ApiToken {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -149,38 +382,254 @@ impl Storable for ApiToken {
 }
 
 // Synthetic Data Generation Structs
-#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub struct SyntheticDataRequest {
     pub dataset_id: String,
     pub num_records: u32,
-    pub privacy_level: String, // "low", "medium", "high"
+    /// "low", "medium" or "high", selecting the differential privacy budget
+    /// epsilon of 8, 2 or 0.5 spent on the statistics the generator fits. The
+    /// column types and personal data masking the generator detects in the
+    /// source are not covered by the budget.
+    pub privacy_level: String,
     pub model_type: String, // "statistical", "medical_gpt", "deep_learning"
     pub preserve_correlations: bool,
     pub hipaa_compliant: bool,
+    /// Seed of the random generator; drawn from `raw_rand` if absent.
+    pub seed: Option<u64>,
+    /// The job fails instead of storing output that exceeds these limits.
+    pub leakage_thresholds: Option<LeakageThresholds>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub struct SyntheticDataJob {
     pub job_id: SyntheticJobId,
     pub dataset_id: String,
     pub owner: PrincipalName,
-    pub status: String, // "pending", "processing", "completed", "failed"
+    pub status: JobStatus,
     pub progress: u8, // 0-100
     pub created_at: u64,
     pub completed_at: Option<u64>,
+    /// ID of the note holding the generated dataset, once the job completed.
     pub result_dataset_id: Option<String>,
     pub error_message: Option<String>,
     pub settings: SyntheticDataRequest,
+    /// Seed the output is sampled with. Rerunning a job with the same seed on
+    /// the same source yields byte-identical output. `None` for jobs created
+    /// before seeds were recorded.
+    pub seed: Option<u64>,
+    /// Differential privacy budget spent on the result, once it is generated.
+    pub epsilon_spent: Option<f64>,
+    /// Privacy leakage of the generated output with respect to the source.
+    pub leakage_report: Option<LeakageReport>,
+    /// ID of a job created while job IDs were strings.
+    pub legacy_id: Option<String>,
+    /// Version of the source dataset the job reads; `None` for jobs created
+    /// before versions were kept, which read the current content.
+    pub source_version: Option<VersionNumber>,
 }
 
 impl SyntheticDataJob {
     pub fn is_authorized(&self, user: &PrincipalName) -> bool {
         user == &self.owner
     }
+
+    /// Moves the job to `status`, recording when it finished.
+    ///
+    /// Errors:
+    ///      the job cannot move from its current status to `status`
+    fn transition(&mut self, status: JobStatus) -> Result<(), Error> {
+        if !self.status.can_become(status) {
+            return Err(Error::InvalidInput(format!(
+                "job {} is {:?} and cannot become {:?}",
+                self.job_id, self.status, status
+            )));
+        }
+        self.status = status;
+        if status.is_finished() {
+            self.completed_at = Some(system::time());
+        }
+        Ok(())
+    }
 }
 
 impl Storable for SyntheticDataJob {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Status of a synthetic job. Jobs start `Pending`, are `Running` once their
+/// model is fitted, and end `Completed`, `Failed` or `Cancelled`.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+
+    fn can_become(self, next: JobStatus) -> bool {
+        use JobStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Running) | (Running, Completed) | (Pending | Running, Failed | Cancelled)
+        )
+    }
+}
+
+/// [SyntheticDataJob] as stored under a string ID, with status type `S`: a
+/// free-form string in the oldest records and [JobStatus] since.
+#[derive(CandidType, Deserialize)]
+struct LegacySyntheticDataJob<S> {
+    job_id: String,
+    dataset_id: String,
+    owner: PrincipalName,
+    status: S,
+    progress: u8,
+    created_at: u64,
+    completed_at: Option<u64>,
+    result_dataset_id: Option<String>,
+    error_message: Option<String>,
+    settings: SyntheticDataRequest,
+    seed: Option<u64>,
+    epsilon_spent: Option<f64>,
+    leakage_report: Option<LeakageReport>,
+}
+
+impl LegacySyntheticDataJob<JobStatus> {
+    fn migrate(self, job_id: SyntheticJobId) -> SyntheticDataJob {
+        SyntheticDataJob {
+            job_id,
+            dataset_id: self.dataset_id,
+            owner: self.owner,
+            status: self.status,
+            progress: self.progress,
+            created_at: self.created_at,
+            completed_at: self.completed_at,
+            result_dataset_id: self.result_dataset_id,
+            error_message: self.error_message,
+            settings: self.settings,
+            seed: self.seed,
+            epsilon_spent: self.epsilon_spent,
+            leakage_report: self.leakage_report,
+            legacy_id: Some(self.job_id),
+            source_version: None,
+        }
+    }
+}
+
+impl From<LegacySyntheticDataJob<String>> for LegacySyntheticDataJob<JobStatus> {
+    fn from(job: LegacySyntheticDataJob<String>) -> Self {
+        let status = match job.status.as_str() {
+            "pending" => JobStatus::Pending,
+            "processing" => JobStatus::Running,
+            // Completion was reported by clients, so only jobs with a result
+            // count as completed.
+            "completed" if job.result_dataset_id.is_some() => JobStatus::Completed,
+            _ => JobStatus::Failed,
+        };
+        Self {
+            job_id: job.job_id,
+            dataset_id: job.dataset_id,
+            owner: job.owner,
+            status,
+            progress: job.progress,
+            created_at: job.created_at,
+            completed_at: job.completed_at,
+            result_dataset_id: job.result_dataset_id,
+            error_message: job.error_message,
+            settings: job.settings,
+            seed: job.seed,
+            epsilon_spent: job.epsilon_spent,
+            leakage_report: job.leakage_report,
+        }
+    }
+}
+
+impl Storable for LegacySyntheticDataJob<JobStatus> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| {
+            Decode!(bytes.as_ref(), LegacySyntheticDataJob<String>)
+                .unwrap()
+                .into()
+        })
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A dataset rewritten by `anonymize_dataset` and the report on it.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnonymizedDataset {
+    pub note_id: NoteId,
+    pub report: AnonymityReport,
+}
+
+/// Differential privacy budget of a dataset: the total epsilon of all
+/// synthetic releases from it may not exceed `cap`.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct PrivacyBudget {
+    pub cap: f64,
+    pub spent: f64,
+}
+
+impl Default for PrivacyBudget {
+    fn default() -> Self {
+        Self {
+            cap: DEFAULT_PRIVACY_BUDGET,
+            spent: 0.0,
+        }
+    }
+}
+
+impl Storable for PrivacyBudget {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// An in-progress chunked upload of the content of note `note_id`.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct UploadSession {
+    id: UploadId,
+    note_id: NoteId,
+    uploader: PrincipalName,
+    total_bytes: u64,
+    received_bytes: u64,
+    chunk_count: u32,
+    created_at: u64,
+    /// SHA-256 of the chunks received so far. `None` for uploads begun before
+    /// it was kept, which have to be started again.
+    sha256_state: Option<Sha256State>,
+}
+
+impl UploadSession {
+    fn is_expired(&self, now: u64) -> bool {
+        now >= self.created_at.saturating_add(UPLOAD_TTL)
+    }
+}
+
+impl Storable for UploadSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -200,6 +649,58 @@ impl NoteIds {
     }
 }
 
+/// Notes shared with a user and the user's role on each.
+#[derive(CandidType, Deserialize, Default)]
+pub struct SharedNotes {
+    shares: Vec<SharedNote>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SharedNote {
+    note_id: NoteId,
+    role: ShareRole,
+}
+
+impl SharedNotes {
+    fn set(&mut self, note_id: NoteId, role: ShareRole) {
+        match self
+            .shares
+            .iter_mut()
+            .find(|share| share.note_id == note_id)
+        {
+            Some(share) => share.role = role,
+            None => self.shares.push(SharedNote { note_id, role }),
+        }
+    }
+
+    fn remove(&mut self, note_id: NoteId) {
+        self.shares.retain(|share| share.note_id != note_id);
+    }
+}
+
+impl Storable for SharedNotes {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        // Shares used to be stored as [NoteIds] and granted full access.
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| {
+            let ids = Decode!(bytes.as_ref(), NoteIds).unwrap();
+            Self {
+                shares: ids
+                    .ids
+                    .into_iter()
+                    .map(|note_id| SharedNote {
+                        note_id,
+                        role: ShareRole::Editor,
+                    })
+                    .collect(),
+            }
+        })
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 #[derive(CandidType, Deserialize, Default)]
 pub struct PassportIds {
     ids: Vec<PassportId>,
@@ -210,6 +711,21 @@ pub struct TokenIds {
     ids: Vec<ApiTokenId>,
 }
 
+#[derive(CandidType, Deserialize, Default)]
+pub struct JobIds {
+    ids: Vec<SyntheticJobId>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct UploadIds {
+    ids: Vec<UploadId>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct MemoryIds {
+    ids: Vec<AgentMemoryId>,
+}
+
 impl PassportIds {
     pub fn iter(&self) -> impl std::iter::Iterator<Item = &PassportId> {
         self.ids.iter()
@@ -223,7 +739,7 @@ impl TokenIds {
 }
 
 impl Storable for PassportIds {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -233,7 +749,49 @@ impl Storable for PassportIds {
 }
 
 impl Storable for TokenIds {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl JobIds {
+    pub fn iter(&self) -> impl std::iter::Iterator<Item = &SyntheticJobId> {
+        self.ids.iter()
+    }
+}
+
+impl MemoryIds {
+    pub fn iter(&self) -> impl std::iter::Iterator<Item = &AgentMemoryId> {
+        self.ids.iter()
+    }
+}
+
+impl Storable for JobIds {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for UploadIds {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MemoryIds {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -243,7 +801,7 @@ impl Storable for TokenIds {
 }
 
 impl Storable for NoteIds {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -264,6 +822,47 @@ static MAX_USERS: u64 = 1_000;
 static MAX_NOTES_PER_USER: usize = 500;
 static MAX_NOTE_CHARS: usize = 1_000_000;
 static MAX_SHARES_PER_NOTE: usize = 50;
+// Chunked uploads keep each chunk well below the 2 MiB ingress message limit.
+static MAX_CHUNK_BYTES: usize = 1_800_000;
+static MAX_UPLOAD_BYTES: u64 = 512 * 1024 * 1024;
+static MAX_UPLOADS_PER_USER: usize = 4;
+// Uncommitted uploads and their chunks are discarded this long after they
+// were begun (one day, in nanoseconds).
+static UPLOAD_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Expired uploads discarded per timer call, each removing up to
+// MAX_UPLOAD_BYTES / MAX_CHUNK_BYTES chunks.
+static UPLOAD_EXPIRY_BATCH: usize = 8;
+// Dataset operations such as profiling and synthesis read content into the
+// heap; larger content can only be downloaded in chunks.
+static MAX_READ_BYTES: u64 = 64 * 1024 * 1024;
+// Older versions of a note are discarded beyond this many.
+static MAX_VERSIONS_PER_NOTE: usize = 20;
+static MAX_TITLE_CHARS: usize = 200;
+static MAX_DESCRIPTION_CHARS: usize = 10_000;
+static DEFAULT_PAGE_SIZE: u32 = 50;
+static MAX_PAGE_SIZE: u32 = 200;
+// Pages stop growing at this encoded size, keeping responses below the
+// message size limit.
+static MAX_PAGE_BYTES: usize = 1_500_000;
+// Privacy budget of a dataset until its owner sets one: one "low" privacy
+// release and one "medium" one.
+static DEFAULT_PRIVACY_BUDGET: f64 = 10.0;
+// Synthetic jobs are processed in timer slices of about this many
+// instructions, well below the limit of 40 billion per message.
+static SLICE_INSTRUCTIONS: u64 = 5_000_000_000;
+// Progress of a synthetic job once its model is fitted, and once its rows
+// are generated.
+static FITTED_PROGRESS: u8 = 5;
+static GENERATED_PROGRESS: u8 = 95;
+// Bytes of the source dataset a synthetic job parses per step.
+static PARSED_BYTES_PER_STEP: usize = 64 * 1024;
+// Largest number of rows a synthetic job may generate.
+static MAX_SYNTHETIC_RECORDS: u32 = 100_000;
+// A synthetic job fails once this many slices in a row were started for it
+// without completing, as when they trap.
+static MAX_SLICE_ATTEMPTS: u32 = 3;
+// Delay after which a slice that did not complete is attempted again.
+static SLICE_RETRY_DELAY: Duration = Duration::from_secs(30);
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
@@ -288,7 +887,7 @@ thread_local! {
         )
     );
 
-    static NOTE_SHARES: RefCell<StableBTreeMap<PrincipalName, NoteIds, Memory>> = RefCell::new(
+    static NOTE_SHARES: RefCell<StableBTreeMap<PrincipalName, SharedNotes, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(3))),
         )
@@ -347,148 +946,614 @@ thread_local! {
         )
     );
 
-    // Synthetic Data Job storage
-    static SYNTHETIC_JOBS: RefCell<StableBTreeMap<SyntheticJobId, SyntheticDataJob, Memory>> = RefCell::new(
+    // Synthetic jobs stored under string IDs, moved to [SYNTHETIC_JOBS] on
+    // upgrade
+    static LEGACY_SYNTHETIC_JOBS: RefCell<StableBTreeMap<String, LegacySyntheticDataJob<JobStatus>, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(12))),
         )
     );
-}
 
-/// Unlike Motoko, the caller identity is not built into Rust.
-/// Thus, we use the ic_cdk::caller() method inside this wrapper function.
-/// The wrapper prevents the use of the anonymous identity. Forbidding anonymous
-/// interactions is the recommended default behavior for IC canisters.
-fn caller() -> Principal {
-    let caller = ic_cdk::caller();
-    // Anonymous principal is not allowed to interact with this canister.
-    if caller == Principal::anonymous() {
-        panic!("Anonymous principal not allowed to make calls.")
-    }
-    caller
-}
+    // Chunked note content. Chunks of open upload sessions and of committed
+    // content share this map and are keyed by the upload that produced them.
+    static NEXT_UPLOAD_ID: RefCell<StableCell<UploadId, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(13))),
+            1
+        ).expect("failed to init NEXT_UPLOAD_ID")
+    );
 
-// --- Queries vs. Updates ---
-//
-// Note that our public methods are declared as an *updates* rather than *queries*, e.g.:
-// #[update(name = "notesCnt")] ...
-// rather than
-// #[query(name = "notesCnt")] ...
-//
-// While queries are significantly faster than updates, they are not certified by the IC.
-// Thus, we avoid using queries throughout this dapp, ensuring that the result of our
-// methods gets through consensus. Otherwise, this method could e.g. omit some notes
-// if it got executed by a malicious node. (To make the dapp more efficient, one could
-// use an approach in which both queries and updates are combined.)
-//
-// See https://internetcomputer.org/docs/current/concepts/canisters-code#query-and-update-methods
+    static UPLOAD_SESSIONS: RefCell<StableBTreeMap<UploadId, UploadSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(14))),
+        )
+    );
 
-/// Reflects the [caller]'s identity by returning (a future of) its principal.
-/// Useful for debugging.
-#[update]
-fn user_str() -> String {
-    ic_cdk::caller().to_string()
-}
+    static CONTENT_CHUNKS: RefCell<StableBTreeMap<(UploadId, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(15))),
+        )
+    );
 
-// General assumptions
-// -------------------
-// All the functions of this canister's public API should be available only to
-// registered users, with the exception of [whoami].
+    // Dataset profiles, keyed by the note they describe
+    static DATA_PROFILES: RefCell<StableBTreeMap<NoteId, DataProfile, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(16))),
+        )
+    );
 
-/// Returns (a future of) this [caller]'s notes.
-/// Panics:
-///     [caller] is the anonymous identity
-#[update]
-fn get_notes() -> Vec<EncryptedNote> {
-    let user_str = caller().to_string();
-    NOTES.with_borrow(|notes| {
-        let owned = NOTE_OWNERS.with_borrow(|ids| {
-            ids.get(&user_str)
-                .unwrap_or_default()
-                .iter()
-                .map(|id| notes.get(id).ok_or(format!("missing note with ID {id}")))
-                .collect::<Result<Vec<_>, _>>()
-                .unwrap_or_else(|err| ic_cdk::trap(&err))
-        });
-        let shared = NOTE_SHARES.with_borrow(|ids| {
-            ids.get(&user_str)
-                .unwrap_or_default()
-                .iter()
-                .map(|id| notes.get(id).ok_or(format!("missing note with ID {id}")))
-                .collect::<Result<Vec<_>, _>>()
-                .unwrap_or_else(|err| ic_cdk::trap(&err))
-        });
-        let mut result = Vec::with_capacity(owned.len() + shared.len());
-        result.extend(owned);
-        result.extend(shared);
-        result
-    })
-}
+    // Privacy budgets of datasets that have a configured cap or releases
+    static PRIVACY_BUDGETS: RefCell<StableBTreeMap<NoteId, PrivacyBudget, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(17))),
+        )
+    );
 
-/// Delete this [caller]'s note with given id. If none of the
-/// existing notes have this id, do nothing.
-/// [id]: the id of the note to be deleted
-///
-/// Returns:
-///      Future of unit
-/// Panics:
-///      [caller] is the anonymous identity
+    // Quasi-identifier and sensitive columns declared by dataset owners
+    static ANONYMITY_CONFIGS: RefCell<StableBTreeMap<NoteId, AnonymityConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(18))),
+        )
+    );
+
+    // Fidelity reports of synthetic jobs stored under string IDs, moved to
+    // [QUALITY_REPORTS] on upgrade
+    static LEGACY_QUALITY_REPORTS: RefCell<StableBTreeMap<String, QualityReport, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(19))),
+        )
+    );
+
+    // Synthetic Data Job storage
+    static NEXT_JOB_ID: RefCell<StableCell<SyntheticJobId, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(20))),
+            1
+        ).expect("failed to init NEXT_JOB_ID")
+    );
+
+    static SYNTHETIC_JOBS: RefCell<StableBTreeMap<SyntheticJobId, SyntheticDataJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(21))),
+        )
+    );
+
+    // Fidelity reports of completed synthetic jobs
+    static QUALITY_REPORTS: RefCell<StableBTreeMap<SyntheticJobId, QualityReport, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(22))),
+        )
+    );
+
+    // Secondary indexes: synthetic jobs by owner and agent memories by passport
+    static JOB_OWNERS: RefCell<StableBTreeMap<PrincipalName, JobIds, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(23))),
+        )
+    );
+
+    static PASSPORT_MEMORIES: RefCell<StableBTreeMap<PassportId, MemoryIds, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(24))),
+        )
+    );
+
+    // Descriptions of note content, keyed by the note they describe
+    static DATASET_METADATA: RefCell<StableBTreeMap<NoteId, DatasetMetadata, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(25))),
+        )
+    );
+
+    // Content history of notes, keyed by note and version number
+    static NOTE_VERSIONS: RefCell<StableBTreeMap<(NoteId, VersionNumber), NoteVersion, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(26))),
+        )
+    );
+
+    // Expiring shares, ordered by expiry time, to prune them once expired
+    static SHARE_EXPIRIES: RefCell<StableBTreeMap<(u64, NoteId, PrincipalName), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(27))),
+        )
+    );
+
+    // Datasets derived from another one, mapped to the dataset whose privacy
+    // budget they draw on
+    static BUDGET_SOURCES: RefCell<StableBTreeMap<NoteId, NoteId, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(28))),
+        )
+    );
+
+    // Number of the last one-time migration applied in [post_upgrade]
+    static SCHEMA_VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(29))),
+            0
+        ).expect("failed to init SCHEMA_VERSION")
+    );
+
+    // Text content of note versions other than the latest one
+    static VERSION_TEXTS: RefCell<StableBTreeMap<(NoteId, VersionNumber), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(30))),
+        )
+    );
+
+    // Note versions read by synthetic jobs, which are not discarded while
+    // the jobs may still run
+    static PINNED_VERSIONS: RefCell<StableBTreeMap<(NoteId, VersionNumber, SyntheticJobId), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(31))),
+        )
+    );
+
+    // Secondary index: open upload sessions by uploader
+    static UPLOAD_OWNERS: RefCell<StableBTreeMap<PrincipalName, UploadIds, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(32))),
+        )
+    );
+
+    // Unfinished synthetic jobs, oldest first, with the number of slices
+    // started for them since the last one that completed
+    static UNFINISHED_JOBS: RefCell<StableBTreeMap<SyntheticJobId, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(33))),
+        )
+    );
+}
+
+// Heap state of synthetic job processing. Timers do not survive upgrades and
+// are scheduled again in [post_upgrade]; interrupted jobs then start over.
+thread_local! {
+    static JOB_RUNS: RefCell<BTreeMap<SyntheticJobId, JobRun>> =
+        const { RefCell::new(BTreeMap::new()) };
+    static JOB_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+// Timers pruning the next expiring share and upload, rescheduled in
+// [post_upgrade].
+thread_local! {
+    static SHARE_EXPIRY_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+    static UPLOAD_EXPIRY_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    migrate_once(1, build_owner_indexes);
+    migrate_once(2, migrate_synthetic_job_ids);
+    migrate_once(3, repair_synthetic_notes);
+    migrate_once(4, remove_invalid_shares);
+    migrate_once(5, split_note_versions);
+    migrate_once(6, index_upload_sessions);
+    migrate_once(7, queue_unfinished_jobs);
+    schedule_synthetic_jobs();
+    schedule_share_expiry();
+    schedule_upload_expiry();
+}
+
+/// Runs `migrate` unless the stored data is already at `version`, so that a
+/// migration scanning all notes runs on a single upgrade only.
+fn migrate_once(version: u32, migrate: fn()) {
+    if SCHEMA_VERSION.with_borrow(|schema| *schema.get()) < version {
+        migrate();
+        SCHEMA_VERSION
+            .with_borrow_mut(|schema| schema.set(version))
+            .expect("failed to set SCHEMA_VERSION");
+    }
+}
+
+/// Indexes the synthetic jobs and agent memories stored before [JOB_OWNERS]
+/// and [PASSPORT_MEMORIES] existed, unless an index was already built.
+fn build_owner_indexes() {
+    if JOB_OWNERS.with_borrow(|owners| owners.is_empty()) {
+        let jobs: Vec<SyntheticDataJob> =
+            SYNTHETIC_JOBS.with_borrow(|jobs| jobs.iter().map(|(_, job)| job).collect());
+        jobs.iter().for_each(index_synthetic_job);
+    }
+    if PASSPORT_MEMORIES.with_borrow(|index| index.is_empty()) {
+        let memories: Vec<(PassportId, AgentMemoryId)> = AGENT_MEMORIES.with_borrow(|memories| {
+            memories
+                .iter()
+                .map(|(id, memory)| (memory.passport_id, id))
+                .collect()
+        });
+        for (passport_id, memory_id) in memories {
+            index_agent_memory(passport_id, memory_id);
+        }
+    }
+}
+
+/// Moves the jobs stored under string IDs, and their quality reports, to
+/// newly allocated IDs in order of creation. The old ID is kept in
+/// `legacy_id`.
+fn migrate_synthetic_job_ids() {
+    let mut legacy_jobs: Vec<(String, LegacySyntheticDataJob<JobStatus>)> =
+        LEGACY_SYNTHETIC_JOBS.with_borrow(|jobs| jobs.iter().collect());
+    legacy_jobs.sort_by_key(|(legacy_id, job)| (job.created_at, legacy_id.clone()));
+    for (legacy_id, job) in legacy_jobs {
+        let job_id = new_job_id();
+        if let Some(report) =
+            LEGACY_QUALITY_REPORTS.with_borrow_mut(|reports| reports.remove(&legacy_id))
+        {
+            QUALITY_REPORTS.with_borrow_mut(|reports| reports.insert(job_id, report));
+        }
+        add_synthetic_job(job.migrate(job_id));
+        LEGACY_SYNTHETIC_JOBS.with_borrow_mut(|jobs| jobs.remove(&legacy_id));
+    }
+}
+
+/// Shares used to be added for any text. Removes those that do not name a
+/// principal, which could not be removed with [remove_user] anymore.
+fn remove_invalid_shares() {
+    let notes: Vec<EncryptedNote> = NOTES.with_borrow(|notes| {
+        notes
+            .iter()
+            .map(|(_, note)| note)
+            .filter(|note| {
+                note.users
+                    .iter()
+                    .any(|share| Principal::from_text(&share.user).is_err())
+            })
+            .collect()
+    });
+    for mut note in notes {
+        let invalid: Vec<PrincipalName> = note
+            .users
+            .iter()
+            .filter(|share| Principal::from_text(&share.user).is_err())
+            .map(|share| share.user.clone())
+            .collect();
+        for user in invalid {
+            unshare(&mut note, &user);
+        }
+        NOTES.with_borrow_mut(|notes| notes.insert(note.id, note));
+    }
+}
+
+/// Synthetic datasets used to be stored with their owner in `users` and
+/// without an entry in [NOTE_OWNERS]. Moves such notes into their owner's
+/// list.
+fn repair_synthetic_notes() {
+    let notes: Vec<EncryptedNote> = NOTES.with_borrow(|notes| {
+        notes
+            .iter()
+            .map(|(_, note)| note)
+            .filter(|note| note.users.iter().any(|share| share.user == note.owner))
+            .collect()
+    });
+    for mut note in notes {
+        NOTE_OWNERS.with_borrow_mut(|owner_to_nids| {
+            let mut owner_nids = owner_to_nids.get(&note.owner).unwrap_or_default();
+            if !owner_nids.ids.contains(&note.id) {
+                owner_nids.ids.push(note.id);
+                owner_to_nids.insert(note.owner.clone(), owner_nids);
+            }
+        });
+        let owner = note.owner.clone();
+        note.users.retain(|share| share.user != owner);
+        NOTES.with_borrow_mut(|notes| notes.insert(note.id, note));
+    }
+}
+
+/// Versions used to be created when a note was first read and to hold a copy
+/// of their text. Records a first version of notes with content but none,
+/// moves the text of older versions to [VERSION_TEXTS], and pins the versions
+/// read by synthetic jobs that may still run.
+fn split_note_versions() {
+    let notes: Vec<EncryptedNote> = NOTES.with_borrow(|notes| {
+        notes
+            .iter()
+            .map(|(_, note)| note)
+            .filter(|note| !note.encrypted_text.is_empty() || note.chunked_content.is_some())
+            .filter(|note| latest_version_number(note.id).is_none())
+            .collect()
+    });
+    for note in notes {
+        let (content_hash, byte_size) = match &note.chunked_content {
+            Some(content) => (content.sha256.to_lowercase(), content.total_bytes),
+            None => (
+                hex::encode(Sha256::digest(note.encrypted_text.as_bytes())),
+                note.encrypted_text.len() as u64,
+            ),
+        };
+        let created_at = DATASET_METADATA
+            .with_borrow(|metadata| metadata.get(&note.id))
+            .map_or_else(system::time, |metadata| metadata.updated_at);
+        let version = NoteVersion {
+            note_id: note.id,
+            version: 1,
+            content_hash,
+            byte_size,
+            author: note.owner.clone(),
+            created_at,
+            encrypted_text: None,
+            chunked_content: note.chunked_content.clone(),
+        };
+        NOTE_VERSIONS.with_borrow_mut(|versions| versions.insert((note.id, 1), version));
+    }
+
+    let with_text: Vec<NoteVersion> = NOTE_VERSIONS.with_borrow(|versions| {
+        versions
+            .iter()
+            .map(|(_, version)| version)
+            .filter(|version| version.encrypted_text.is_some())
+            .collect()
+    });
+    for mut version in with_text {
+        let key = (version.note_id, version.version);
+        let text = version.encrypted_text.take().unwrap_or_default();
+        if latest_version_number(version.note_id) != Some(version.version) {
+            VERSION_TEXTS.with_borrow_mut(|texts| texts.insert(key, text));
+        }
+        NOTE_VERSIONS.with_borrow_mut(|versions| versions.insert(key, version));
+    }
+
+    let jobs: Vec<SyntheticDataJob> =
+        SYNTHETIC_JOBS.with_borrow(|jobs| jobs.iter().map(|(_, job)| job).collect());
+    jobs.iter().for_each(pin_source_version);
+}
+
+/// Indexes the upload sessions begun before [UPLOAD_OWNERS] existed.
+fn index_upload_sessions() {
+    let sessions: Vec<UploadSession> = UPLOAD_SESSIONS
+        .with_borrow(|sessions| sessions.iter().map(|(_, session)| session).collect());
+    sessions.iter().for_each(index_upload_session);
+}
+
+/// Queues the synthetic jobs left unfinished before [UNFINISHED_JOBS] existed.
+fn queue_unfinished_jobs() {
+    let unfinished: Vec<SyntheticJobId> = SYNTHETIC_JOBS.with_borrow(|jobs| {
+        jobs.iter()
+            .filter(|(_, job)| !job.status.is_finished())
+            .map(|(job_id, _)| job_id)
+            .collect()
+    });
+    for job_id in unfinished {
+        UNFINISHED_JOBS.with_borrow_mut(|queue| queue.insert(job_id, 0));
+    }
+}
+
+/// Unlike Motoko, the caller identity is not built into Rust.
+/// Thus, we use the ic_cdk::api::msg_caller() method inside this wrapper function.
+/// The wrapper prevents the use of the anonymous identity. Forbidding anonymous
+/// interactions is the recommended default behavior for IC canisters.
+fn caller() -> Result<Principal, Error> {
+    let caller = system::msg_caller();
+    // Anonymous principal is not allowed to interact with this canister.
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized(
+            "anonymous principal not allowed to make calls".to_string(),
+        ));
+    }
+    Ok(caller)
+}
+
+// --- Queries vs. Updates ---
+//
+// Note that our public methods are declared as an *updates* rather than *queries*, e.g.:
+// #[update(name = "notesCnt")] ...
+// rather than
+// #[query(name = "notesCnt")] ...
+//
+// While queries are significantly faster than updates, they are not certified by the IC.
+// Thus, we avoid using queries throughout this dapp, ensuring that the result of our
+// methods gets through consensus. Otherwise, this method could e.g. omit some notes
+// if it got executed by a malicious node. (To make the dapp more efficient, one could
+// use an approach in which both queries and updates are combined.)
+//
+// See https://internetcomputer.org/docs/current/concepts/canisters-code#query-and-update-methods
+
+/// Reflects the [caller]'s identity by returning (a future of) its principal.
+/// Useful for debugging.
+#[update]
+fn user_str() -> String {
+    system::msg_caller().to_string()
+}
+
+// General assumptions
+// -------------------
+// All the functions of this canister's public API should be available only to
+// registered users, with the exception of [user_str].
+//
+// Failures are reported as an [Error] rather than by trapping, so that clients
+// can tell them apart. Every method validates its input before modifying any
+// state, because returning an error (unlike a trap) does not roll back changes.
+
+/// Returns a page of the notes this [caller] owns or that are shared with
+/// them, with their encrypted text if `include_content` is set.
+/// Errors:
+///     [caller] is the anonymous identity
+///     `page.cursor` is malformed
+#[update]
+fn get_notes(page: PageRequest, include_content: bool) -> Result<Page<NoteListing>, Error> {
+    let user_str = caller()?.to_string();
+    let mut ids = NOTE_OWNERS
+        .with_borrow(|ids| ids.get(&user_str))
+        .unwrap_or_default()
+        .ids;
+    let shared = NOTE_SHARES.with_borrow(|shares| shares.get(&user_str).unwrap_or_default());
+    ids.extend(shared.shares.iter().map(|share| share.note_id));
+    NOTES.with_borrow(|notes| {
+        paginate(ids, &page, |id| {
+            let note = notes
+                .get(&id)
+                .ok_or(Error::Internal(format!("missing note with ID {id}")))?;
+            // Notes whose share with [caller] expired are left out, even before
+            // the share is pruned.
+            Ok(note
+                .is_authorized(&user_str)
+                .then(|| NoteListing::new(note, include_content)))
+        })
+    })
+}
+
+/// Returns the page of the items with `ids` selected by `page`, in ascending
+/// ID order. `load` returns the item with the given ID, or `None` to leave it
+/// out of the listing. Items that could exceed [MAX_PAGE_BYTES] on their own
+/// must be shortened by `load`, as the first item is always returned.
+fn paginate<T: CandidType>(
+    mut ids: Vec<u128>,
+    page: &PageRequest,
+    mut load: impl FnMut(u128) -> Result<Option<T>, Error>,
+) -> Result<Page<T>, Error> {
+    let after = match &page.cursor {
+        Some(cursor) => Some(
+            cursor
+                .parse::<u128>()
+                .map_err(|_| Error::InvalidInput(format!("invalid cursor {cursor:?}")))?,
+        ),
+        None => None,
+    };
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as usize;
+    ids.sort_unstable();
+    ids.dedup();
+    ids.retain(|&id| after.is_none_or(|after| id > after));
+
+    let mut items = Vec::new();
+    let mut bytes = 0;
+    let mut last = None;
+    for &id in &ids {
+        if items.len() == limit {
+            break;
+        }
+        if let Some(item) = load(id)? {
+            bytes += Encode!(&item).map_or(0, |encoded| encoded.len());
+            if bytes > MAX_PAGE_BYTES && !items.is_empty() {
+                break;
+            }
+            items.push(item);
+        }
+        last = Some(id);
+    }
+    let next_cursor = last
+        .filter(|&last| ids.last() != Some(&last))
+        .map(|last| last.to_string());
+    Ok(Page { items, next_cursor })
+}
+
+/// Delete this [caller]'s note with given id.
+/// [id]: the id of the note to be deleted
+///
+/// Returns:
+///      Future of unit
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
 ///      [caller] is not the owner of note with id `note_id`
 #[update]
-fn delete_note(note_id: u128) {
-    let user_str = caller().to_string();
+fn delete_note(note_id: u128) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    let note = NOTES
+        .with_borrow(|notes| notes.get(&note_id))
+        .ok_or_else(|| note_not_found(note_id))?;
+    if note.owner != user_str {
+        return Err(Error::Unauthorized(
+            "only the owner can delete notes".to_string(),
+        ));
+    }
+    remove_note(note_id);
+    Ok(())
+}
+
+/// Removes note `note_id` together with its content and everything derived
+/// from it. (internal function)
+fn remove_note(note_id: NoteId) {
     NOTES.with_borrow_mut(|notes| {
-        if let Some(note_to_delete) = notes.get(&note_id) {
-            let owner = &note_to_delete.owner;
-            if owner != &user_str {
-                ic_cdk::trap("only the owner can delete notes");
+        let Some(note_to_delete) = notes.get(&note_id) else {
+            return;
+        };
+        let owner = &note_to_delete.owner;
+        NOTE_OWNERS.with_borrow_mut(|owner_to_nids| {
+            if let Some(mut owner_ids) = owner_to_nids.get(owner) {
+                owner_ids.ids.retain(|&id| id != note_id);
+                if !owner_ids.ids.is_empty() {
+                    owner_to_nids.insert(owner.clone(), owner_ids);
+                } else {
+                    owner_to_nids.remove(owner);
+                }
             }
-            NOTE_OWNERS.with_borrow_mut(|owner_to_nids| {
-                if let Some(mut owner_ids) = owner_to_nids.get(owner) {
-                    owner_ids.ids.retain(|&id| id != note_id);
-                    if !owner_ids.ids.is_empty() {
-                        owner_to_nids.insert(owner.clone(), owner_ids);
+        });
+        NOTE_SHARES.with_borrow_mut(|user_to_shares| {
+            for share in note_to_delete.users.iter() {
+                if let Some(mut shared) = user_to_shares.get(&share.user) {
+                    shared.remove(note_id);
+                    if !shared.shares.is_empty() {
+                        user_to_shares.insert(share.user.clone(), shared);
                     } else {
-                        owner_to_nids.remove(owner);
+                        user_to_shares.remove(&share.user);
                     }
                 }
-            });
-            NOTE_SHARES.with_borrow_mut(|share_to_nids| {
-                for share in note_to_delete.users {
-                    if let Some(mut share_ids) = share_to_nids.get(&share) {
-                        share_ids.ids.retain(|&id| id != note_id);
-                        if !share_ids.ids.is_empty() {
-                            share_to_nids.insert(share, share_ids);
-                        } else {
-                            share_to_nids.remove(&share);
-                        }
-                    }
-                }
-            });
-            notes.remove(&note_id);
+            }
+        });
+        for share in &note_to_delete.users {
+            unindex_share_expiry(note_id, share);
         }
-    });
+        if let Some(content) = &note_to_delete.chunked_content {
+            remove_content_chunks(content.upload_id, content.chunk_count);
+        }
+        for version in version_numbers(note_id) {
+            remove_version(note_id, version);
+        }
+        PINNED_VERSIONS.with_borrow_mut(|pins| {
+            let pinned: Vec<_> = pins
+                .keys_range((note_id, 0, 0)..=(note_id, VersionNumber::MAX, SyntheticJobId::MAX))
+                .collect();
+            for key in pinned {
+                pins.remove(&key);
+            }
+        });
+        DATA_PROFILES.with_borrow_mut(|profiles| profiles.remove(&note_id));
+        // A budget is kept while datasets derived from its dataset draw on it.
+        let source = BUDGET_SOURCES.with_borrow_mut(|sources| sources.remove(&note_id));
+        let orphaned_source = source.filter(|source| !notes.contains_key(source));
+        for budget_id in std::iter::once(note_id).chain(orphaned_source) {
+            let has_derived = BUDGET_SOURCES
+                .with_borrow(|sources| sources.iter().any(|(_, source)| source == budget_id));
+            if !has_derived {
+                PRIVACY_BUDGETS.with_borrow_mut(|budgets| budgets.remove(&budget_id));
+            }
+        }
+        ANONYMITY_CONFIGS.with_borrow_mut(|configs| configs.remove(&note_id));
+        DATASET_METADATA.with_borrow_mut(|metadata| metadata.remove(&note_id));
+        notes.remove(&note_id);
+    })
 }
 
 /// Replaces the encrypted text of note with ID [id] with [encrypted_text].
 ///
-/// Panics:
+/// Errors:
 ///     [caller] is the anonymous identity
-///     [caller] is not the note's owner and not a user with whom the note is shared
+///     note with ID [id] does not exist
+///     [caller] is not the note's owner and not an editor or co-owner of the note
 ///     [encrypted_text] exceeds [MAX_NOTE_CHARS]
 #[update]
-fn update_note(id: NoteId, encrypted_text: String) {
-    let user_str = caller().to_string();
+fn update_note(id: NoteId, encrypted_text: String) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
 
     NOTES.with_borrow_mut(|notes| {
-        if let Some(mut note_to_update) = notes.get(&id) {
-            if !note_to_update.is_authorized(&user_str) {
-                ic_cdk::trap("unauthorized update");
-            }
-            assert!(encrypted_text.chars().count() <= MAX_NOTE_CHARS);
-            note_to_update.encrypted_text = encrypted_text;
-            notes.insert(id, note_to_update);
+        let mut note_to_update = notes.get(&id).ok_or_else(|| note_not_found(id))?;
+        if !note_to_update.has_role(&user_str, ShareRole::Editor) {
+            return Err(Error::Unauthorized("unauthorized update".to_string()));
+        }
+        if encrypted_text.chars().count() > MAX_NOTE_CHARS {
+            return Err(Error::QuotaExceeded(format!(
+                "note text exceeds {MAX_NOTE_CHARS} characters"
+            )));
         }
+        archive_content(&note_to_update);
+        note_to_update.chunked_content = None;
+        note_to_update.encrypted_text = encrypted_text;
+        content_changed(&note_to_update, &user_str);
+        notes.insert(id, note_to_update);
+        DATA_PROFILES.with_borrow_mut(|profiles| profiles.remove(&id));
+        Ok(())
     })
 }
 
@@ -496,14 +1561,23 @@ fn update_note(id: NoteId, encrypted_text: String) {
 ///
 /// Returns:
 ///      Future of ID of new empty note
-/// Panics:
+/// Errors:
 ///      [caller] is the anonymous identity
 ///      User already has [MAX_NOTES_PER_USER] notes
 ///      This is the first note for [caller] and [MAX_USERS] is exceeded
 #[update]
-fn create_note() -> NoteId {
-    let owner = caller().to_string();
+fn create_note() -> Result<NoteId, Error> {
+    let owner = caller()?.to_string();
+    insert_note(owner, String::new(), None)
+}
 
+/// Adds a new note of `owner` with content `encrypted_text` or
+/// `chunked_content`, subject to the same limits as [create_note].
+fn insert_note(
+    owner: PrincipalName,
+    encrypted_text: String,
+    chunked_content: Option<ChunkedContent>,
+) -> Result<NoteId, Error> {
     NOTES.with_borrow_mut(|id_to_note| {
         NOTE_OWNERS.with_borrow_mut(|owner_to_nids| {
             let next_note_id = NEXT_NOTE_ID.with_borrow(|id| *id.get());
@@ -511,15 +1585,24 @@ fn create_note() -> NoteId {
                 id: next_note_id,
                 owner: owner.clone(),
                 users: vec![],
-                encrypted_text: String::new(),
+                encrypted_text,
+                chunked_content,
             };
 
             if let Some(mut owner_nids) = owner_to_nids.get(&owner) {
-                assert!(owner_nids.ids.len() < MAX_NOTES_PER_USER);
+                if owner_nids.ids.len() >= MAX_NOTES_PER_USER {
+                    return Err(Error::QuotaExceeded(format!(
+                        "users can have at most {MAX_NOTES_PER_USER} notes"
+                    )));
+                }
                 owner_nids.ids.push(new_note.id);
                 owner_to_nids.insert(owner, owner_nids);
             } else {
-                assert!(owner_to_nids.len() < MAX_USERS);
+                if owner_to_nids.len() >= MAX_USERS {
+                    return Err(Error::QuotaExceeded(format!(
+                        "the canister is limited to {MAX_USERS} users"
+                    )));
+                }
                 owner_to_nids.insert(
                     owner,
                     NoteIds {
@@ -527,6 +1610,7 @@ fn create_note() -> NoteId {
                     },
                 );
             }
+            content_changed(&new_note, &new_note.owner);
             assert_eq!(id_to_note.insert(new_note.id, new_note), None);
 
             NEXT_NOTE_ID.with_borrow_mut(|next_note_id| {
@@ -538,134 +1622,1162 @@ fn create_note() -> NoteId {
                     .set(id_plus_one)
                     .unwrap_or_else(|_e| ic_cdk::trap("failed to set NEXT_NOTE_ID"))
             });
-            next_note_id
+            Ok(next_note_id)
         })
     })
 }
 
-/// Shares the note with ID `note_id`` with the `user`.
-/// Has no effect if the note is already shared with that user.
+/// Adds a new note of `owner` holding the dataset `text`, generated by the
+/// canister. Text longer than [MAX_NOTE_CHARS] is stored in chunks.
+fn insert_dataset(owner: PrincipalName, text: String) -> Result<NoteId, Error> {
+    let mut dataset = DatasetText::default();
+    dataset.push(&text);
+    dataset.insert(owner)
+}
+
+/// Text of a dataset generated by the canister, collected a part at a time
+/// and split into chunks of [MAX_CHUNK_BYTES] as it grows.
+#[derive(Default)]
+struct DatasetText {
+    /// Text not yet split into `chunks`.
+    pending: Vec<u8>,
+    chunks: Vec<Vec<u8>>,
+    sha256: Sha256State,
+    chunked_bytes: u64,
+}
+
+impl DatasetText {
+    fn push(&mut self, text: &str) {
+        self.pending.extend_from_slice(text.as_bytes());
+        while self.pending.len() > MAX_CHUNK_BYTES {
+            let rest = self.pending.split_off(MAX_CHUNK_BYTES);
+            let chunk = std::mem::replace(&mut self.pending, rest);
+            self.add_chunk(chunk);
+        }
+    }
+
+    fn add_chunk(&mut self, chunk: Vec<u8>) {
+        self.sha256.update(&chunk);
+        self.chunked_bytes += chunk.len() as u64;
+        self.chunks.push(chunk);
+    }
+
+    /// Adds a new note of `owner` holding the text, stored in chunks unless
+    /// it has at most [MAX_NOTE_CHARS] characters.
+    fn insert(mut self, owner: PrincipalName) -> Result<NoteId, Error> {
+        if self.chunks.is_empty() {
+            let text = String::from_utf8(std::mem::take(&mut self.pending))
+                .expect("dataset text is valid UTF-8");
+            if text.chars().count() <= MAX_NOTE_CHARS {
+                return insert_note(owner, text, None);
+            }
+            self.pending = text.into_bytes();
+        }
+        let last = std::mem::take(&mut self.pending);
+        if !last.is_empty() {
+            self.add_chunk(last);
+        }
+        let upload_id = new_upload_id();
+        let chunk_count = self.chunks.len() as u32;
+        let parts = std::mem::take(&mut self.chunks);
+        CONTENT_CHUNKS.with_borrow_mut(|chunks| {
+            for (index, chunk) in parts.into_iter().enumerate() {
+                chunks.insert((upload_id, index as u32), chunk);
+            }
+        });
+        let content = ChunkedContent {
+            upload_id,
+            chunk_count,
+            total_bytes: self.chunked_bytes,
+            sha256: self.sha256.finalize(),
+        };
+        insert_note(owner, String::new(), Some(content)).inspect_err(|_| {
+            remove_content_chunks(upload_id, chunk_count);
+        })
+    }
+}
+
+/// Shares the note with ID `note_id`` with the `user` in role `role` until
+/// `expires_at` (nanoseconds since the epoch), or indefinitely without it.
+/// Changes the role and expiry if the note is already shared with that user.
 ///
-/// Panics:
+/// Errors:
 ///      [caller] is the anonymous identity
-///      [caller] is not the owner of note with id `note_id`
+///      note with id `note_id` does not exist
+///      [caller] is not the owner or a co-owner of note with id `note_id`
+///      `user` is not a valid principal, is anonymous or owns the note
+///      `expires_at` is not in the future
+///      the note is already shared with [MAX_SHARES_PER_NOTE] users
 #[update]
-fn add_user(note_id: NoteId, user: PrincipalName) {
-    let caller_str = caller().to_string();
-    NOTES.with_borrow_mut(|notes| {
-        NOTE_SHARES.with_borrow_mut(|user_to_nids| {
-            if let Some(mut note) = notes.get(&note_id) {
-                let owner = &note.owner;
-                if owner != &caller_str {
-                    ic_cdk::trap("only the owner can share the note");
-                }
-                assert!(note.users.len() < MAX_SHARES_PER_NOTE);
-                if !note.users.contains(&user) {
-                    note.users.push(user.clone());
-                    notes.insert(note_id, note);
-                }
-                if let Some(mut user_ids) = user_to_nids.get(&user) {
-                    if !user_ids.ids.contains(&note_id) {
-                        user_ids.ids.push(note_id);
-                        user_to_nids.insert(user, user_ids);
-                    }
-                } else {
-                    user_to_nids.insert(user, NoteIds { ids: vec![note_id] });
-                }
-            }
-        })
+fn add_user(
+    note_id: NoteId,
+    user: PrincipalName,
+    role: ShareRole,
+    expires_at: Option<u64>,
+) -> Result<(), Error> {
+    let caller_str = caller()?.to_string();
+    let mut note = NOTES
+        .with_borrow(|notes| notes.get(&note_id))
+        .ok_or_else(|| note_not_found(note_id))?;
+    if !note.has_role(&caller_str, ShareRole::CoOwner) {
+        return Err(Error::Unauthorized(
+            "only the owner and co-owners can share the note".to_string(),
+        ));
+    }
+    let user = share_user(&note, &user)?;
+    if expires_at.is_some_and(|expires_at| expires_at <= system::time()) {
+        return Err(Error::InvalidInput(
+            "share expiry must be in the future".to_string(),
+        ));
+    }
+    let share = NoteShare {
+        user: user.clone(),
+        role,
+        expires_at,
+    };
+    match note.users.iter().position(|share| share.user == user) {
+        Some(idx) => {
+            unindex_share_expiry(note_id, &note.users[idx]);
+            note.users[idx] = share;
+        }
+        None if note.users.len() >= MAX_SHARES_PER_NOTE => {
+            return Err(Error::QuotaExceeded(format!(
+                "notes can be shared with at most {MAX_SHARES_PER_NOTE} users"
+            )));
+        }
+        None => note.users.push(share),
+    }
+    NOTES.with_borrow_mut(|notes| notes.insert(note_id, note));
+
+    NOTE_SHARES.with_borrow_mut(|user_to_shares| {
+        let mut shared = user_to_shares.get(&user).unwrap_or_default();
+        shared.set(note_id, role);
+        user_to_shares.insert(user.clone(), shared);
     });
+    if let Some(expires_at) = expires_at {
+        SHARE_EXPIRIES.with_borrow_mut(|expiries| expiries.insert((expires_at, note_id, user), ()));
+        schedule_share_expiry();
+    }
+    Ok(())
 }
 
 /// Unshares the note with ID `note_id`` with the `user`.
 /// Has no effect if the note is not shared with that user.
 ///
-/// Panics:
+/// Errors:
 ///      [caller] is the anonymous identity
-///      [caller] is not the owner of note with id `note_id`
+///      note with id `note_id` does not exist
+///      [caller] is not the owner or a co-owner of note with id `note_id`
+///      `user` is not a valid principal, is anonymous or owns the note
 #[update]
-fn remove_user(note_id: NoteId, user: PrincipalName) {
-    let caller_str = caller().to_string();
-    NOTES.with_borrow_mut(|notes| {
-        NOTE_SHARES.with_borrow_mut(|user_to_nids| {
-            if let Some(mut note) = notes.get(&note_id) {
-                let owner = &note.owner;
-                if owner != &caller_str {
-                    ic_cdk::trap("only the owner can share the note");
-                }
-                note.users.retain(|u| u != &user);
-                notes.insert(note_id, note);
+fn remove_user(note_id: NoteId, user: PrincipalName) -> Result<(), Error> {
+    let caller_str = caller()?.to_string();
+    let mut note = NOTES
+        .with_borrow(|notes| notes.get(&note_id))
+        .ok_or_else(|| note_not_found(note_id))?;
+    if !note.has_role(&caller_str, ShareRole::CoOwner) {
+        return Err(Error::Unauthorized(
+            "only the owner and co-owners can share the note".to_string(),
+        ));
+    }
+    let user = share_user(&note, &user)?;
+    unshare(&mut note, &user);
+    NOTES.with_borrow_mut(|notes| notes.insert(note_id, note));
+    Ok(())
+}
 
-                if let Some(mut user_ids) = user_to_nids.get(&user) {
-                    user_ids.ids.retain(|&id| id != note_id);
-                    if !user_ids.ids.is_empty() {
-                        user_to_nids.insert(user, user_ids);
-                    } else {
-                        user_to_nids.remove(&user);
-                    }
-                }
+/// Parses `user` as a principal that `note` can be shared with and returns
+/// its canonical text.
+fn share_user(note: &EncryptedNote, user: &str) -> Result<PrincipalName, Error> {
+    let principal = Principal::from_text(user)
+        .map_err(|e| Error::InvalidPrincipal(format!("invalid principal {user:?}: {e}")))?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidInput(
+            "notes cannot be shared with the anonymous principal".to_string(),
+        ));
+    }
+    let user = principal.to_text();
+    if user == note.owner {
+        return Err(Error::InvalidInput(
+            "notes cannot be shared with their owner".to_string(),
+        ));
+    }
+    Ok(user)
+}
+
+/// Removes the share of `note` with `user` from the note and from the indexes;
+/// the caller stores the note.
+fn unshare(note: &mut EncryptedNote, user: &PrincipalName) {
+    let note_id = note.id;
+    if let Some(idx) = note.users.iter().position(|share| &share.user == user) {
+        unindex_share_expiry(note_id, &note.users.remove(idx));
+    }
+    NOTE_SHARES.with_borrow_mut(|user_to_shares| {
+        if let Some(mut shared) = user_to_shares.get(user) {
+            shared.remove(note_id);
+            if !shared.shares.is_empty() {
+                user_to_shares.insert(user.clone(), shared);
+            } else {
+                user_to_shares.remove(user);
+            }
+        }
+    });
+}
+
+fn unindex_share_expiry(note_id: NoteId, share: &NoteShare) {
+    if let Some(expires_at) = share.expires_at {
+        SHARE_EXPIRIES.with_borrow_mut(|expiries| {
+            expiries.remove(&(expires_at, note_id, share.user.clone()))
+        });
+    }
+}
+
+/// Sets a timer for the next share to expire, replacing any earlier one.
+fn schedule_share_expiry() {
+    if let Some(timer) = SHARE_EXPIRY_TIMER.with(|timer| timer.take()) {
+        system::clear_timer(timer);
+    }
+    let Some((expires_at, _, _)) =
+        SHARE_EXPIRIES.with_borrow(|expiries| expiries.first_key_value().map(|(key, _)| key))
+    else {
+        return;
+    };
+    let delay = Duration::from_nanos(expires_at.saturating_sub(system::time()));
+    let timer_id = system::set_timer(delay, || {
+        SHARE_EXPIRY_TIMER.with(|timer| timer.set(None));
+        prune_expired_shares();
+        schedule_share_expiry();
+    });
+    SHARE_EXPIRY_TIMER.with(|timer| timer.set(Some(timer_id)));
+}
+
+/// Removes the shares that have expired from their notes and [NOTE_SHARES].
+fn prune_expired_shares() {
+    let now = system::time();
+    let expired: Vec<(u64, NoteId, PrincipalName)> = SHARE_EXPIRIES.with_borrow(|expiries| {
+        expiries
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|(expires_at, _, _)| *expires_at <= now)
+            .collect()
+    });
+    for key in expired {
+        SHARE_EXPIRIES.with_borrow_mut(|expiries| expiries.remove(&key));
+        let (_, note_id, user) = key;
+        let Some(mut note) = NOTES.with_borrow(|notes| notes.get(&note_id)) else {
+            continue;
+        };
+        unshare(&mut note, &user);
+        NOTES.with_borrow_mut(|notes| notes.insert(note_id, note));
+    }
+}
+
+fn note_not_found(note_id: NoteId) -> Error {
+    Error::NotFound(format!("note with ID {note_id} does not exist"))
+}
+
+// ===== CHUNKED CONTENT FUNCTIONS =====
+
+/// Starts a chunked upload that will replace the content of note `note_id`
+/// with `total_bytes` bytes once committed.
+///
+/// Returns:
+///      ID of the new upload session
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not an editor or co-owner of the note
+///      `total_bytes` exceeds [MAX_UPLOAD_BYTES]
+///      [caller] already has [MAX_UPLOADS_PER_USER] open upload sessions
+#[update]
+fn begin_note_upload(note_id: NoteId, total_bytes: u64) -> Result<UploadId, Error> {
+    let user_str = caller()?.to_string();
+
+    NOTES.with_borrow(|notes| match notes.get(&note_id) {
+        Some(note) if note.has_role(&user_str, ShareRole::Editor) => Ok(()),
+        Some(_) => Err(Error::Unauthorized("unauthorized upload".to_string())),
+        None => Err(note_not_found(note_id)),
+    })?;
+    if total_bytes > MAX_UPLOAD_BYTES {
+        return Err(Error::QuotaExceeded(format!(
+            "uploads are limited to {MAX_UPLOAD_BYTES} bytes"
+        )));
+    }
+
+    let open = UPLOAD_OWNERS.with_borrow(|owners| owners.get(&user_str).unwrap_or_default());
+    if open.ids.len() >= MAX_UPLOADS_PER_USER {
+        return Err(Error::QuotaExceeded(format!(
+            "users can have at most {MAX_UPLOADS_PER_USER} open uploads"
+        )));
+    }
+
+    let upload_id = new_upload_id();
+    let session = UploadSession {
+        id: upload_id,
+        note_id,
+        uploader: user_str,
+        total_bytes,
+        received_bytes: 0,
+        chunk_count: 0,
+        created_at: system::time(),
+        sha256_state: Some(Sha256State::default()),
+    };
+    index_upload_session(&session);
+    UPLOAD_SESSIONS.with_borrow_mut(|sessions| sessions.insert(upload_id, session));
+    if UPLOAD_EXPIRY_TIMER.with(|timer| timer.get().is_none()) {
+        schedule_upload_expiry();
+    }
+    Ok(upload_id)
+}
+
+/// Stores `data` as chunk number `chunk_index` of upload `upload_id`.
+/// Chunks must be appended in order. Re-sending the last received chunk with
+/// the same data has no effect, so a failed call can simply be retried.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      upload `upload_id` does not exist or has expired
+///      [caller] did not begin upload `upload_id`
+///      `chunk_index` is not the next chunk, or the last received chunk with other data
+///      `data` exceeds [MAX_CHUNK_BYTES] or the upload's declared size
+#[update]
+fn append_note_chunk(upload_id: UploadId, chunk_index: u32, data: Vec<u8>) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    if data.len() > MAX_CHUNK_BYTES {
+        return Err(Error::QuotaExceeded(format!(
+            "chunks are limited to {MAX_CHUNK_BYTES} bytes"
+        )));
+    }
+    let mut session = uploader_session(upload_id, &user_str)?;
+    if chunk_index.checked_add(1) == Some(session.chunk_count) {
+        let received = CONTENT_CHUNKS.with_borrow(|chunks| chunks.get(&(upload_id, chunk_index)));
+        if received.as_ref() == Some(&data) {
+            return Ok(());
+        }
+        return Err(Error::InvalidInput(format!(
+            "chunk {chunk_index} was already received with other data"
+        )));
+    }
+    if chunk_index != session.chunk_count {
+        return Err(Error::InvalidInput(format!(
+            "expected chunk {} but got chunk {chunk_index}",
+            session.chunk_count
+        )));
+    }
+    let received_bytes = session.received_bytes + data.len() as u64;
+    if received_bytes > session.total_bytes {
+        return Err(Error::InvalidInput(format!(
+            "upload exceeds its declared size of {} bytes",
+            session.total_bytes
+        )));
+    }
+    let Some(state) = session.sha256_state.as_mut() else {
+        return Err(upload_restart_needed(upload_id));
+    };
+    state.update(&data);
+    session.received_bytes = received_bytes;
+    session.chunk_count += 1;
+    CONTENT_CHUNKS.with_borrow_mut(|chunks| chunks.insert((upload_id, chunk_index), data));
+    UPLOAD_SESSIONS.with_borrow_mut(|sessions| sessions.insert(upload_id, session));
+    Ok(())
+}
+
+/// Returns the metadata of dataset `note_id`, describing its content first if
+/// it has not been described yet.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+#[update]
+fn get_dataset_metadata(note_id: NoteId) -> Result<DatasetMetadata, Error> {
+    let user_str = caller()?.to_string();
+    let note = authorized_note(note_id, &user_str)?;
+    Ok(dataset_metadata(&note))
+}
+
+/// Sets the title and description of dataset `note_id`.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      `title` exceeds [MAX_TITLE_CHARS] or `description` [MAX_DESCRIPTION_CHARS]
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not an editor or co-owner of the note
+#[update]
+fn set_dataset_metadata(note_id: NoteId, title: String, description: String) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    if title.chars().count() > MAX_TITLE_CHARS {
+        return Err(Error::InvalidInput(format!(
+            "title exceeds {MAX_TITLE_CHARS} characters"
+        )));
+    }
+    if description.chars().count() > MAX_DESCRIPTION_CHARS {
+        return Err(Error::InvalidInput(format!(
+            "description exceeds {MAX_DESCRIPTION_CHARS} characters"
+        )));
+    }
+    let note = editable_note(note_id, &user_str)?;
+    let mut metadata = dataset_metadata(&note);
+    metadata.title = title;
+    metadata.description = description;
+    metadata.updated_at = system::time();
+    DATASET_METADATA.with_borrow_mut(|all| all.insert(note_id, metadata));
+    Ok(())
+}
+
+fn dataset_metadata(note: &EncryptedNote) -> DatasetMetadata {
+    match DATASET_METADATA.with_borrow(|metadata| metadata.get(&note.id)) {
+        Some(metadata) => metadata,
+        None => describe_note(note),
+    }
+}
+
+/// Updates the metadata of `note` after its content changed, creating the
+/// metadata of a raw dataset if it has none.
+fn describe_note(note: &EncryptedNote) -> DatasetMetadata {
+    let now = system::time();
+    let mut metadata = DATASET_METADATA
+        .with_borrow(|metadata| metadata.get(&note.id))
+        .unwrap_or_else(|| DatasetMetadata::new(DatasetKind::Raw, now));
+    match &note.chunked_content {
+        Some(content) => {
+            let first_chunk = CONTENT_CHUNKS
+                .with_borrow(|chunks| chunks.get(&(content.upload_id, 0)))
+                .unwrap_or_default();
+            metadata.describe_chunked_content(
+                content.total_bytes,
+                &content.sha256,
+                &first_chunk,
+                now,
+            );
+        }
+        None => metadata.describe_content(note.encrypted_text.as_bytes(), now),
+    }
+    DATASET_METADATA.with_borrow_mut(|all| all.insert(note.id, metadata.clone()));
+    metadata
+}
+
+/// Completes upload `upload_id` and makes it the content of its note,
+/// discarding the note's previous content.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      upload `upload_id` does not exist or has expired, or its note does not exist
+///      [caller] did not begin upload `upload_id`
+///      fewer bytes than declared in [begin_note_upload] were received
+///      `sha256` (hex) does not match the SHA-256 of the received chunks
+#[update]
+fn commit_note_upload(upload_id: UploadId, sha256: String) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+
+    let session = uploader_session(upload_id, &user_str)?;
+    if session.received_bytes != session.total_bytes {
+        return Err(Error::InvalidInput(format!(
+            "received {} of {} bytes",
+            session.received_bytes, session.total_bytes
+        )));
+    }
+
+    let digest = session
+        .sha256_state
+        .clone()
+        .ok_or_else(|| upload_restart_needed(upload_id))?
+        .finalize();
+    if !digest.eq_ignore_ascii_case(&sha256) {
+        return Err(Error::InvalidInput(format!(
+            "checksum mismatch: expected {sha256}, got {digest}"
+        )));
+    }
+
+    NOTES.with_borrow_mut(|notes| {
+        let mut note = notes
+            .get(&session.note_id)
+            .ok_or_else(|| note_not_found(session.note_id))?;
+        if !note.has_role(&user_str, ShareRole::Editor) {
+            return Err(Error::Unauthorized("unauthorized upload".to_string()));
+        }
+        archive_content(&note);
+        note.encrypted_text = String::new();
+        note.chunked_content = Some(ChunkedContent {
+            upload_id,
+            chunk_count: session.chunk_count,
+            total_bytes: session.total_bytes,
+            sha256: digest,
+        });
+        content_changed(&note, &user_str);
+        notes.insert(session.note_id, note);
+        DATA_PROFILES.with_borrow_mut(|profiles| profiles.remove(&session.note_id));
+        Ok(())
+    })?;
+    close_upload(&session);
+    Ok(())
+}
+
+/// Discards upload `upload_id` and all chunks received so far.
+/// Has no effect if the upload does not exist.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      [caller] did not begin upload `upload_id`
+#[update]
+fn abort_note_upload(upload_id: UploadId) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    match uploader_session(upload_id, &user_str) {
+        Ok(session) => {
+            remove_content_chunks(upload_id, session.chunk_count);
+            close_upload(&session);
+            Ok(())
+        }
+        Err(Error::NotFound(_)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns upload session `upload_id` if `user` began it and it has not
+/// expired.
+fn uploader_session(upload_id: UploadId, user: &PrincipalName) -> Result<UploadSession, Error> {
+    let session = UPLOAD_SESSIONS
+        .with_borrow(|sessions| sessions.get(&upload_id))
+        .filter(|session| !session.is_expired(system::time()))
+        .ok_or_else(|| upload_not_found(upload_id))?;
+    if &session.uploader != user {
+        return Err(Error::Unauthorized(
+            "only the uploader can use an upload".to_string(),
+        ));
+    }
+    Ok(session)
+}
+
+fn index_upload_session(session: &UploadSession) {
+    UPLOAD_OWNERS.with_borrow_mut(|owners| {
+        let mut uploads = owners.get(&session.uploader).unwrap_or_default();
+        uploads.ids.push(session.id);
+        owners.insert(session.uploader.clone(), uploads);
+    });
+}
+
+/// Removes `session`, leaving its chunks in place.
+fn close_upload(session: &UploadSession) {
+    UPLOAD_OWNERS.with_borrow_mut(|owners| {
+        if let Some(mut uploads) = owners.get(&session.uploader) {
+            uploads.ids.retain(|&id| id != session.id);
+            if !uploads.ids.is_empty() {
+                owners.insert(session.uploader.clone(), uploads);
+            } else {
+                owners.remove(&session.uploader);
             }
+        }
+    });
+    UPLOAD_SESSIONS.with_borrow_mut(|sessions| sessions.remove(&session.id));
+}
+
+/// Sets a timer for the oldest upload to expire, replacing any earlier one.
+/// Upload IDs increase with time, so the first session is the oldest.
+fn schedule_upload_expiry() {
+    if let Some(timer) = UPLOAD_EXPIRY_TIMER.with(|timer| timer.take()) {
+        system::clear_timer(timer);
+    }
+    let Some(created_at) = UPLOAD_SESSIONS.with_borrow(|sessions| {
+        sessions
+            .first_key_value()
+            .map(|(_, session)| session.created_at)
+    }) else {
+        return;
+    };
+    let expires_at = created_at.saturating_add(UPLOAD_TTL);
+    let delay = Duration::from_nanos(expires_at.saturating_sub(system::time()));
+    let timer_id = system::set_timer(delay, || {
+        UPLOAD_EXPIRY_TIMER.with(|timer| timer.set(None));
+        prune_expired_uploads();
+        schedule_upload_expiry();
+    });
+    UPLOAD_EXPIRY_TIMER.with(|timer| timer.set(Some(timer_id)));
+}
+
+/// Discards up to [UPLOAD_EXPIRY_BATCH] expired uploads and their chunks.
+fn prune_expired_uploads() {
+    let now = system::time();
+    let expired: Vec<UploadSession> = UPLOAD_SESSIONS.with_borrow(|sessions| {
+        sessions
+            .iter()
+            .map(|(_, session)| session)
+            .take_while(|session| session.is_expired(now))
+            .take(UPLOAD_EXPIRY_BATCH)
+            .collect()
+    });
+    for session in expired {
+        remove_content_chunks(session.id, session.chunk_count);
+        close_upload(&session);
+    }
+}
+
+/// Returns chunk number `chunk_index` of the content of note `note_id`. The
+/// number of chunks is given by the note's `chunked_content` or, for text
+/// left out of a listing, by its `omitted_content_chunks`: text is split into
+/// chunks of [MAX_CHUNK_BYTES] bytes.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+///      the note has fewer chunks
+#[update]
+fn get_note_chunk(note_id: NoteId, chunk_index: u32) -> Result<Vec<u8>, Error> {
+    let user_str = caller()?.to_string();
+    let note = authorized_note(note_id, &user_str)?;
+    let Some(content) = note.chunked_content else {
+        let text = note.encrypted_text.as_bytes();
+        let chunk_count = text_chunk_count(&note.encrypted_text);
+        if chunk_index >= chunk_count {
+            return Err(Error::InvalidInput(format!(
+                "chunk {chunk_index} out of range, note has {chunk_count} chunks"
+            )));
+        }
+        let start = chunk_index as usize * MAX_CHUNK_BYTES;
+        let end = text.len().min(start + MAX_CHUNK_BYTES);
+        return Ok(text[start..end].to_vec());
+    };
+    if chunk_index >= content.chunk_count {
+        return Err(Error::InvalidInput(format!(
+            "chunk {chunk_index} out of range, note has {} chunks",
+            content.chunk_count
+        )));
+    }
+    CONTENT_CHUNKS
+        .with_borrow(|chunks| chunks.get(&(content.upload_id, chunk_index)))
+        .ok_or_else(|| Error::Internal(format!("missing chunk {chunk_index} of note {note_id}")))
+}
+
+/// Number of chunks of [MAX_CHUNK_BYTES] that [get_note_chunk] splits `text`
+/// into; empty text is a single empty chunk.
+fn text_chunk_count(text: &str) -> u32 {
+    text.len().div_ceil(MAX_CHUNK_BYTES).max(1) as u32
+}
+
+/// Returns the full content of `note` as bytes, reassembling chunked content
+/// of up to [MAX_READ_BYTES].
+/// Chunked content is not required to be valid UTF-8 as a whole; dataset
+/// readers such as [csv::Reader] report encoding errors per row.
+fn note_bytes(note: &EncryptedNote) -> Result<Vec<u8>, Error> {
+    content_bytes(note.id, &note.encrypted_text, &note.chunked_content)
+}
+
+fn content_bytes(
+    note_id: NoteId,
+    encrypted_text: &str,
+    chunked_content: &Option<ChunkedContent>,
+) -> Result<Vec<u8>, Error> {
+    match chunked_content {
+        None => Ok(encrypted_text.as_bytes().to_vec()),
+        Some(content) => {
+            check_readable(note_id, content)?;
+            let mut bytes = Vec::with_capacity(content.total_bytes as usize);
+            CONTENT_CHUNKS.with_borrow(|chunks| {
+                for index in 0..content.chunk_count {
+                    let chunk = chunks.get(&(content.upload_id, index)).ok_or_else(|| {
+                        Error::Internal(format!("missing chunk {index} of note {note_id}"))
+                    })?;
+                    bytes.extend_from_slice(&chunk);
+                }
+                Ok(bytes)
+            })
+        }
+    }
+}
+
+/// Checks that the content of note `note_id` can be read into memory.
+fn check_readable(note_id: NoteId, content: &ChunkedContent) -> Result<(), Error> {
+    if content.total_bytes > MAX_READ_BYTES {
+        return Err(Error::QuotaExceeded(format!(
+            "note {note_id} has {} bytes, more than the {MAX_READ_BYTES} that can be processed",
+            content.total_bytes
+        )));
+    }
+    Ok(())
+}
+
+fn new_upload_id() -> UploadId {
+    NEXT_UPLOAD_ID.with_borrow_mut(|next_id| {
+        let id = *next_id.get();
+        let id_plus_one = id
+            .checked_add(1)
+            .expect("failed to increase NEXT_UPLOAD_ID: reached the maximum");
+        next_id
+            .set(id_plus_one)
+            .unwrap_or_else(|_e| ic_cdk::trap("failed to set NEXT_UPLOAD_ID"));
+        id
+    })
+}
+
+fn remove_content_chunks(upload_id: UploadId, chunk_count: u32) {
+    CONTENT_CHUNKS.with_borrow_mut(|chunks| {
+        for index in 0..chunk_count {
+            chunks.remove(&(upload_id, index));
+        }
+    });
+}
+
+fn upload_not_found(upload_id: UploadId) -> Error {
+    Error::NotFound(format!("upload with ID {upload_id} does not exist"))
+}
+
+fn upload_restart_needed(upload_id: UploadId) -> Error {
+    Error::InvalidInput(format!(
+        "upload {upload_id} was begun before checksums were kept, begin it again"
+    ))
+}
+
+// ===== NOTE VERSION FUNCTIONS =====
+
+/// Returns a page of the versions of note `note_id` without their content.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+///      `page.cursor` is malformed
+#[update]
+fn get_note_versions(note_id: NoteId, page: PageRequest) -> Result<Page<NoteVersion>, Error> {
+    let user_str = caller()?.to_string();
+    authorized_note(note_id, &user_str)?;
+    let ids = version_numbers(note_id)
+        .into_iter()
+        .map(u128::from)
+        .collect();
+    paginate(ids, &page, |version| {
+        Ok(note_version(note_id, version as VersionNumber))
+    })
+}
+
+/// Returns version `version` of note `note_id` with its content. Chunked
+/// content is read with [get_note_version_chunk].
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+///      the version does not exist or was discarded
+#[update]
+fn get_note_version(note_id: NoteId, version: VersionNumber) -> Result<NoteVersion, Error> {
+    let user_str = caller()?.to_string();
+    let note = authorized_note(note_id, &user_str)?;
+    let mut found =
+        note_version(note_id, version).ok_or_else(|| version_not_found(note_id, version))?;
+    found.encrypted_text = Some(version_text(&note, version));
+    Ok(found)
+}
+
+/// Returns chunk number `chunk_index` of the chunked content of version
+/// `version` of note `note_id`.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+///      the version does not exist, has no chunked content or fewer chunks
+#[update]
+fn get_note_version_chunk(
+    note_id: NoteId,
+    version: VersionNumber,
+    chunk_index: u32,
+) -> Result<Vec<u8>, Error> {
+    let user_str = caller()?.to_string();
+    authorized_note(note_id, &user_str)?;
+    let content = note_version(note_id, version)
+        .ok_or_else(|| version_not_found(note_id, version))?
+        .chunked_content
+        .ok_or_else(|| Error::InvalidInput("version content is not chunked".to_string()))?;
+    if chunk_index >= content.chunk_count {
+        return Err(Error::InvalidInput(format!(
+            "chunk {chunk_index} out of range, version has {} chunks",
+            content.chunk_count
+        )));
+    }
+    CONTENT_CHUNKS
+        .with_borrow(|chunks| chunks.get(&(content.upload_id, chunk_index)))
+        .ok_or_else(|| Error::Internal(format!("missing chunk {chunk_index} of note {note_id}")))
+}
+
+/// Makes the content of version `version` of note `note_id` its current
+/// content, recorded as a new version, and returns the new version number.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not an editor or co-owner of the note
+///      the version does not exist or was discarded
+#[update]
+fn restore_note_version(note_id: NoteId, version: VersionNumber) -> Result<VersionNumber, Error> {
+    let user_str = caller()?.to_string();
+    let mut note = editable_note(note_id, &user_str)?;
+    let restored =
+        note_version(note_id, version).ok_or_else(|| version_not_found(note_id, version))?;
+    let restored_text = version_text(&note, version);
+    archive_content(&note);
+    note.encrypted_text = restored_text;
+    note.chunked_content = restored.chunked_content;
+    let new_version = content_changed(&note, &user_str);
+    NOTES.with_borrow_mut(|notes| notes.insert(note_id, note));
+    DATA_PROFILES.with_borrow_mut(|profiles| profiles.remove(&note_id));
+    Ok(new_version)
+}
+
+/// Describes the new content of `note` and records it as a version created by
+/// `author`, discarding the oldest versions beyond [MAX_VERSIONS_PER_NOTE]
+/// that no synthetic job is pinned to. Returns the new version number.
+fn content_changed(note: &EncryptedNote, author: &PrincipalName) -> VersionNumber {
+    let metadata = describe_note(note);
+    let version = latest_version_number(note.id).map_or(1, |latest| latest + 1);
+    let snapshot = NoteVersion {
+        note_id: note.id,
+        version,
+        content_hash: metadata.content_hash,
+        byte_size: metadata.byte_size,
+        author: author.clone(),
+        created_at: system::time(),
+        encrypted_text: None,
+        chunked_content: note.chunked_content.clone(),
+    };
+    NOTE_VERSIONS.with_borrow_mut(|versions| versions.insert((note.id, version), snapshot));
+
+    let older = version_numbers(note.id);
+    let mut excess = older.len().saturating_sub(MAX_VERSIONS_PER_NOTE);
+    for &old in &older[..older.len() - 1] {
+        if excess == 0 {
+            break;
+        }
+        if !is_pinned(note.id, old) {
+            remove_version(note.id, old);
+            excess -= 1;
+        }
+    }
+    version
+}
+
+/// Keeps the text of the latest version of `note` before its content is
+/// replaced. Until then, the note's content is that text.
+fn archive_content(note: &EncryptedNote) {
+    if note.chunked_content.is_some() {
+        return;
+    }
+    if let Some(latest) = latest_version_number(note.id) {
+        VERSION_TEXTS
+            .with_borrow_mut(|texts| texts.insert((note.id, latest), note.encrypted_text.clone()));
+    }
+}
+
+/// Text content of version `version` of `note`, empty for chunked content.
+fn version_text(note: &EncryptedNote, version: VersionNumber) -> String {
+    if latest_version_number(note.id) == Some(version) {
+        return note.encrypted_text.clone();
+    }
+    VERSION_TEXTS
+        .with_borrow(|texts| texts.get(&(note.id, version)))
+        .unwrap_or_default()
+}
+
+fn remove_version(note_id: NoteId, version: VersionNumber) {
+    let removed = NOTE_VERSIONS.with_borrow_mut(|versions| versions.remove(&(note_id, version)));
+    VERSION_TEXTS.with_borrow_mut(|texts| texts.remove(&(note_id, version)));
+    release_chunks(note_id, removed.and_then(|version| version.chunked_content));
+}
+
+/// Keeps the version of the dataset that `job` reads from being discarded
+/// while the job may still run or be retried.
+fn pin_source_version(job: &SyntheticDataJob) {
+    if let (Some(version), Ok(note_id), false) = (
+        job.source_version,
+        dataset_note_id(&job.dataset_id),
+        job.status == JobStatus::Completed,
+    ) {
+        PINNED_VERSIONS.with_borrow_mut(|pins| pins.insert((note_id, version, job.job_id), ()));
+    }
+}
+
+fn unpin_source_version(job: &SyntheticDataJob) {
+    if let (Some(version), Ok(note_id)) = (job.source_version, dataset_note_id(&job.dataset_id)) {
+        PINNED_VERSIONS.with_borrow_mut(|pins| pins.remove(&(note_id, version, job.job_id)));
+    }
+}
+
+fn is_pinned(note_id: NoteId, version: VersionNumber) -> bool {
+    PINNED_VERSIONS.with_borrow(|pins| {
+        pins.keys_range((note_id, version, 0)..=(note_id, version, SyntheticJobId::MAX))
+            .next()
+            .is_some()
+    })
+}
+
+/// Removes the chunks of `content` unless another version of note `note_id`
+/// still refers to them.
+fn release_chunks(note_id: NoteId, content: Option<ChunkedContent>) {
+    let Some(content) = content else {
+        return;
+    };
+    let in_use = note_versions(note_id).any(|version| {
+        version
+            .chunked_content
+            .is_some_and(|other| other.upload_id == content.upload_id)
+    });
+    if !in_use {
+        remove_content_chunks(content.upload_id, content.chunk_count);
+    }
+}
+
+fn note_versions(note_id: NoteId) -> impl Iterator<Item = NoteVersion> {
+    NOTE_VERSIONS
+        .with_borrow(|versions| {
+            versions
+                .range((note_id, 0)..=(note_id, VersionNumber::MAX))
+                .map(|(_, version)| version)
+                .collect::<Vec<_>>()
         })
+        .into_iter()
+}
+
+fn note_version(note_id: NoteId, version: VersionNumber) -> Option<NoteVersion> {
+    NOTE_VERSIONS.with_borrow(|versions| versions.get(&(note_id, version)))
+}
+
+/// Numbers of the versions of note `note_id`, oldest first, read without
+/// decoding the versions.
+fn version_numbers(note_id: NoteId) -> Vec<VersionNumber> {
+    NOTE_VERSIONS.with_borrow(|versions| {
+        versions
+            .keys_range((note_id, 0)..=(note_id, VersionNumber::MAX))
+            .map(|(_, version)| version)
+            .collect()
     })
 }
 
-// VetKD functions temporarily disabled for compilation
-// #[update]
-// async fn symmetric_key_verification_key_for_note() -> String {
-//     let request = VetKDPublicKeyArgs {
-//         canister_id: None,
-//         context: b"note_symmetric_key".to_vec(),
-//         key_id: bls12_381_g2_test_key_1(),
-//     };
-//
-//     let response: VetKDPublicKeyResult = ic_cdk::management_canister::vetkd_public_key(&request)
-//         .await
-//         .expect("call to vetkd_public_key failed");
-//
-//     hex::encode(response.public_key)
-// }
-//
-// #[update]
-// async fn encrypted_symmetric_key_for_note(
-//     note_id: NoteId,
-//     transport_public_key: Vec<u8>,
-// ) -> String {
-//     let user_str = caller().to_string();
-//     let request = NOTES.with_borrow(|notes| {
-//         if let Some(note) = notes.get(&note_id) {
-//             if !note.is_authorized(&user_str) {
-//                 ic_cdk::trap(&format!("unauthorized key request by user {user_str}"));
-//             }
-//             VetKDDeriveKeyArgs {
-//                 input: {
-//                     let mut buf = vec![];
-//                     buf.extend_from_slice(&note_id.to_be_bytes()); // fixed-size encoding
-//                     buf.extend_from_slice(note.owner.as_bytes());
-//                     buf // prefix-free
-//                 },
-//                 context: b"note_symmetric_key".to_vec(),
-//                 key_id: bls12_381_g2_test_key_1(),
-//                 transport_public_key,
-//             }
-//         } else {
-//             ic_cdk::trap(&format!("note with ID {note_id} does not exist"));
-//         }
-//     });
-//
-//     let response: VetKDDeriveKeyResult = ic_cdk::management_canister::vetkd_derive_key(&request)
-//         .await
-//         .expect("call to vetkd_derive_key failed");
-//
-//     hex::encode(response.encrypted_key)
-// }
+fn latest_version_number(note_id: NoteId) -> Option<VersionNumber> {
+    NOTE_VERSIONS.with_borrow(|versions| {
+        versions
+            .keys_range((note_id, 0)..=(note_id, VersionNumber::MAX))
+            .next_back()
+            .map(|(_, version)| version)
+    })
+}
+
+fn version_not_found(note_id: NoteId, version: VersionNumber) -> Error {
+    Error::NotFound(format!(
+        "version {version} of note with ID {note_id} does not exist"
+    ))
+}
+
+// ===== DATASET PROFILING FUNCTIONS =====
+
+/// Profiles the CSV content of note `note_id` and stores the result, replacing
+/// any previous profile. Profiles are discarded when the note's content changes.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+///      the note does not contain a header row
+#[update]
+fn analyze_dataset(note_id: NoteId) -> Result<DataProfile, Error> {
+    let user_str = caller()?.to_string();
+    let note = authorized_note(note_id, &user_str)?;
+    let table = csv::Table::read(&note_bytes(&note)?);
+    if table.headers.is_empty() {
+        return Err(Error::InvalidInput("Empty dataset".to_string()));
+    }
+
+    let profile = profile::analyze_table(&table, note_id, system::time());
+    DATA_PROFILES.with_borrow_mut(|profiles| profiles.insert(note_id, profile.clone()));
+    Ok(profile)
+}
+
+/// Returns the stored profile of note `note_id`, if it was analyzed since its
+/// content last changed.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+#[update]
+fn get_dataset_profile(note_id: NoteId) -> Result<Option<DataProfile>, Error> {
+    let user_str = caller()?.to_string();
+    authorized_note(note_id, &user_str)?;
+    Ok(DATA_PROFILES.with_borrow(|profiles| profiles.get(&note_id)))
+}
+
+/// Returns note `note_id` if `user` may read it.
+fn authorized_note(note_id: NoteId, user: &PrincipalName) -> Result<EncryptedNote, Error> {
+    let note = NOTES
+        .with_borrow(|notes| notes.get(&note_id))
+        .ok_or_else(|| note_not_found(note_id))?;
+    if !note.is_authorized(user) {
+        return Err(Error::Unauthorized(format!(
+            "no access to note with ID {note_id}"
+        )));
+    }
+    Ok(note)
+}
+
+/// Returns note `note_id` if `user` may change it.
+fn editable_note(note_id: NoteId, user: &PrincipalName) -> Result<EncryptedNote, Error> {
+    let note = authorized_note(note_id, user)?;
+    if !note.has_role(user, ShareRole::Editor) {
+        return Err(Error::Unauthorized(format!(
+            "read-only access to note with ID {note_id}"
+        )));
+    }
+    Ok(note)
+}
 
-// VetKD key function temporarily disabled
-// fn bls12_381_g2_test_key_1() -> VetKDKeyId {
-//     VetKDKeyId {
-//         curve: VetKDCurve::Bls12_381_G2,
-//         name: "test_key_1".to_string(),
-//     }
-// }
+// ===== ANONYMITY FUNCTIONS =====
+
+/// Declares the quasi-identifier and sensitive columns of dataset `note_id`.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner
+///      no quasi-identifier is declared or a column is not in the dataset
+#[update]
+fn set_anonymity_config(note_id: NoteId, config: AnonymityConfig) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    let note = owned_note(note_id, &user_str)?;
+    let table = csv::Table::read(&note_bytes(&note)?);
+    anonymity::measure(&table, &config).map_err(Error::InvalidInput)?;
+    ANONYMITY_CONFIGS.with_borrow_mut(|configs| configs.insert(note_id, config));
+    Ok(())
+}
+
+/// Measures k-anonymity and l-diversity of dataset `note_id` with respect to
+/// its declared columns.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+///      no columns are declared for the note or they are not in the dataset
+#[update]
+fn measure_anonymity(note_id: NoteId) -> Result<AnonymityReport, Error> {
+    let user_str = caller()?.to_string();
+    let note = authorized_note(note_id, &user_str)?;
+    let config = anonymity_config(note_id)?;
+    let table = csv::Table::read(&note_bytes(&note)?);
+    anonymity::measure(&table, &config).map_err(Error::InvalidInput)
+}
+
+/// Generalizes and suppresses the declared quasi-identifiers of dataset
+/// `note_id` until it is `k`-anonymous and `l`-diverse, and stores the result
+/// as a new note of [caller] with the same declared columns. Synthetic jobs on
+/// the result are charged to the privacy budget of `note_id`.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner
+///      no columns are declared for the note or they are not in the dataset
+///      the targets cannot be met without suppressing every row
+///      [caller] already has [MAX_NOTES_PER_USER] notes
+#[update]
+fn anonymize_dataset(note_id: NoteId, k: u32, l: u32) -> Result<AnonymizedDataset, Error> {
+    let user_str = caller()?.to_string();
+    let note = owned_note(note_id, &user_str)?;
+    let config = anonymity_config(note_id)?;
+    let table = csv::Table::read(&note_bytes(&note)?);
+    let (content, report) =
+        anonymity::anonymize(&table, &config, k, l).map_err(Error::InvalidInput)?;
+    let new_note_id = insert_dataset(user_str, content)?;
+    ANONYMITY_CONFIGS.with_borrow_mut(|configs| configs.insert(new_note_id, config));
+    BUDGET_SOURCES.with_borrow_mut(|sources| sources.insert(new_note_id, budget_note_id(note_id)));
+    Ok(AnonymizedDataset {
+        note_id: new_note_id,
+        report,
+    })
+}
+
+fn anonymity_config(note_id: NoteId) -> Result<AnonymityConfig, Error> {
+    ANONYMITY_CONFIGS
+        .with_borrow(|configs| configs.get(&note_id))
+        .ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "no quasi-identifier columns declared for note with ID {note_id}"
+            ))
+        })
+}
+
+/// Returns note `note_id` if `user` owns it.
+fn owned_note(note_id: NoteId, user: &PrincipalName) -> Result<EncryptedNote, Error> {
+    let note = NOTES
+        .with_borrow(|notes| notes.get(&note_id))
+        .ok_or_else(|| note_not_found(note_id))?;
+    if &note.owner != user {
+        return Err(Error::Unauthorized(format!(
+            "only the owner of note with ID {note_id} can do this"
+        )));
+    }
+    Ok(note)
+}
+
+#[update]
+async fn symmetric_key_verification_key_for_note() -> Result<String, Error> {
+    caller()?;
+    let request = VetKDPublicKeyArgs {
+        canister_id: None,
+        context: b"note_symmetric_key".to_vec(),
+        key_id: bls12_381_g2_key_id(),
+    };
+
+    let response: VetKDPublicKeyResult = ic_cdk::management_canister::vetkd_public_key(&request)
+        .await
+        .map_err(|e| Error::Internal(format!("call to vetkd_public_key failed: {e}")))?;
+
+    Ok(hex::encode(response.public_key))
+}
+
+#[update]
+async fn encrypted_symmetric_key_for_note(
+    note_id: NoteId,
+    transport_public_key: Vec<u8>,
+) -> Result<String, Error> {
+    let user_str = caller()?.to_string();
+    let request = NOTES.with_borrow(|notes| {
+        let note = notes.get(&note_id).ok_or_else(|| note_not_found(note_id))?;
+        if !note.is_authorized(&user_str) {
+            return Err(Error::Unauthorized(format!(
+                "unauthorized key request by user {user_str}"
+            )));
+        }
+        Ok(VetKDDeriveKeyArgs {
+            input: {
+                let mut buf = vec![];
+                buf.extend_from_slice(&note_id.to_be_bytes()); // fixed-size encoding
+                buf.extend_from_slice(note.owner.as_bytes());
+                buf // prefix-free
+            },
+            context: b"note_symmetric_key".to_vec(),
+            key_id: bls12_381_g2_key_id(),
+            transport_public_key,
+        })
+    })?;
+
+    let response: VetKDDeriveKeyResult = ic_cdk::management_canister::vetkd_derive_key(&request)
+        .await
+        .map_err(|e| Error::Internal(format!("call to vetkd_derive_key failed: {e}")))?;
+
+    Ok(hex::encode(response.encrypted_key))
+}
+
+/// The vetKD master key used for note keys. Builds with the `local-test-key`
+/// feature use `dfx_test_key`, which is the only key provisioned by a local
+/// replica; all other builds use `test_key_1`.
+fn bls12_381_g2_key_id() -> VetKDKeyId {
+    let name = if cfg!(feature = "local-test-key") {
+        "dfx_test_key"
+    } else {
+        "test_key_1"
+    };
+    VetKDKeyId {
+        curve: VetKDCurve::Bls12_381_G2,
+        name: name.to_string(),
+    }
+}
 
 // ===== AGENT PASSPORT FUNCTIONS =====
 
@@ -677,14 +2789,14 @@ fn create_agent_passport(
     agent_type: String,
     capabilities: Vec<String>,
     encrypted_specifications: String,
-) -> PassportId {
-    let owner = caller().to_string();
-    let current_time = ic_cdk::api::time();
+) -> Result<PassportId, Error> {
+    let owner = caller()?.to_string();
+    let current_time = system::time();
 
     PASSPORTS.with_borrow_mut(|passports| {
         PASSPORT_OWNERS.with_borrow_mut(|owners| {
             let next_passport_id = NEXT_PASSPORT_ID.with_borrow(|id| *id.get());
-            
+
             let new_passport = AgentPassport {
                 id: next_passport_id,
                 agent_name,
@@ -725,62 +2837,62 @@ fn create_agent_passport(
                     .unwrap_or_else(|_e| ic_cdk::trap("failed to set NEXT_PASSPORT_ID"))
             });
 
-            next_passport_id
+            Ok(next_passport_id)
         })
     })
 }
 
 /// Retrieves an agent passport by ID
 #[update]
-fn get_agent_passport(passport_id: PassportId) -> Option<AgentPassport> {
-    let user_str = caller().to_string();
+fn get_agent_passport(passport_id: PassportId) -> Result<Option<AgentPassport>, Error> {
+    let user_str = caller()?.to_string();
     PASSPORTS.with_borrow(|passports| {
         if let Some(passport) = passports.get(&passport_id) {
             if passport.is_authorized(&user_str) {
-                Some(passport)
+                Ok(Some(passport))
             } else {
-                ic_cdk::trap("unauthorized access to passport");
+                Err(Error::Unauthorized(
+                    "unauthorized access to passport".to_string(),
+                ))
             }
         } else {
-            None
+            Ok(None)
         }
     })
 }
 
-/// Returns all passports owned by the caller
+/// Returns a page of the passports owned by the caller
 #[update]
-fn get_my_passports() -> Vec<AgentPassport> {
-    let user_str = caller().to_string();
-    PASSPORTS.with_borrow(|passports| {
-        PASSPORT_OWNERS.with_borrow(|owners| {
-            if let Some(passport_ids) = owners.get(&user_str) {
-                passport_ids
-                    .iter()
-                    .filter_map(|id| passports.get(id))
-                    .collect()
-            } else {
-                vec![]
-            }
-        })
-    })
+fn get_my_passports(page: PageRequest) -> Result<Page<AgentPassport>, Error> {
+    let user_str = caller()?.to_string();
+    let ids = PASSPORT_OWNERS
+        .with_borrow(|owners| owners.get(&user_str))
+        .unwrap_or_default()
+        .ids;
+    PASSPORTS.with_borrow(|passports| paginate(ids, &page, |id| Ok(passports.get(&id))))
 }
 
 /// Updates agent specifications
 #[update]
-fn update_agent_specifications(passport_id: PassportId, encrypted_specifications: String) {
-    let user_str = caller().to_string();
+fn update_agent_specifications(
+    passport_id: PassportId,
+    encrypted_specifications: String,
+) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
     PASSPORTS.with_borrow_mut(|passports| {
-        if let Some(mut passport) = passports.get(&passport_id) {
-            if !passport.is_authorized(&user_str) {
-                ic_cdk::trap("unauthorized update to passport");
-            }
-            passport.encrypted_specifications = encrypted_specifications;
-            passport.last_active = ic_cdk::api::time();
-            passports.insert(passport_id, passport);
-        } else {
-            ic_cdk::trap("passport not found");
+        let mut passport = passports
+            .get(&passport_id)
+            .ok_or_else(|| passport_not_found(passport_id))?;
+        if !passport.is_authorized(&user_str) {
+            return Err(Error::Unauthorized(
+                "unauthorized update to passport".to_string(),
+            ));
         }
-    });
+        passport.encrypted_specifications = encrypted_specifications;
+        passport.last_active = system::time();
+        passports.insert(passport_id, passport);
+        Ok(())
+    })
 }
 
 /// Adds a memory entry for an agent
@@ -790,34 +2902,27 @@ fn add_agent_memory(
     memory_type: String,
     encrypted_content: String,
     importance_score: u8,
-) -> AgentMemoryId {
-    let owner = caller().to_string();
-    
+) -> Result<AgentMemoryId, Error> {
+    let owner = caller()?.to_string();
+
     // Verify passport ownership
-    PASSPORTS.with_borrow(|passports| {
-        if let Some(passport) = passports.get(&passport_id) {
-            if !passport.is_authorized(&owner) {
-                ic_cdk::trap("unauthorized access to passport");
-            }
-        } else {
-            ic_cdk::trap("passport not found");
-        }
-    });
+    authorize_passport(passport_id, &owner)?;
 
     AGENT_MEMORIES.with_borrow_mut(|memories| {
         let next_memory_id = NEXT_MEMORY_ID.with_borrow(|id| *id.get());
-        
+
         let new_memory = AgentMemory {
             id: next_memory_id,
             passport_id,
             memory_type,
             encrypted_content,
             importance_score: importance_score.min(100), // Cap at 100
-            created_at: ic_cdk::api::time(),
+            created_at: system::time(),
             owner,
         };
 
         memories.insert(next_memory_id, new_memory);
+        index_agent_memory(passport_id, next_memory_id);
 
         // Increment the next memory ID
         NEXT_MEMORY_ID.with_borrow_mut(|next_id| {
@@ -830,310 +2935,1202 @@ fn add_agent_memory(
                 .unwrap_or_else(|_e| ic_cdk::trap("failed to set NEXT_MEMORY_ID"))
         });
 
-        next_memory_id
+        Ok(next_memory_id)
     })
 }
 
-/// Retrieves memories for an agent passport
+/// Retrieves a page of the memories of an agent passport
 #[update]
-fn get_agent_memories(passport_id: PassportId, memory_type: Option<String>) -> Vec<AgentMemory> {
-    let user_str = caller().to_string();
-    
+fn get_agent_memories(
+    passport_id: PassportId,
+    memory_type: Option<String>,
+    page: PageRequest,
+) -> Result<Page<AgentMemory>, Error> {
+    let user_str = caller()?.to_string();
+
     // Verify passport ownership
-    PASSPORTS.with_borrow(|passports| {
-        if let Some(passport) = passports.get(&passport_id) {
-            if !passport.is_authorized(&user_str) {
-                ic_cdk::trap("unauthorized access to passport");
-            }
-        } else {
-            ic_cdk::trap("passport not found");
-        }
-    });
+    authorize_passport(passport_id, &user_str)?;
 
+    let memory_ids = PASSPORT_MEMORIES
+        .with_borrow(|index| index.get(&passport_id))
+        .unwrap_or_default()
+        .ids;
     AGENT_MEMORIES.with_borrow(|memories| {
-        memories
-            .iter()
-            .filter_map(|(_, memory)| {
-                if memory.passport_id == passport_id && memory.is_authorized(&user_str) {
-                    if let Some(ref filter_type) = memory_type {
-                        if &memory.memory_type == filter_type {
-                            Some(memory)
-                        } else {
-                            None
-                        }
-                    } else {
-                        Some(memory)
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect()
+        paginate(memory_ids, &page, |id| {
+            Ok(memories.get(&id).filter(|memory| {
+                memory.is_authorized(&user_str)
+                    && memory_type
+                        .as_ref()
+                        .is_none_or(|filter_type| &memory.memory_type == filter_type)
+            }))
+        })
+    })
+}
+
+fn index_agent_memory(passport_id: PassportId, memory_id: AgentMemoryId) {
+    PASSPORT_MEMORIES.with_borrow_mut(|index| {
+        let mut memory_ids = index.get(&passport_id).unwrap_or_default();
+        memory_ids.ids.push(memory_id);
+        index.insert(passport_id, memory_ids);
+    });
+}
+
+/// Checks that passport `passport_id` exists and is owned by `user`.
+fn authorize_passport(passport_id: PassportId, user: &PrincipalName) -> Result<(), Error> {
+    PASSPORTS.with_borrow(|passports| {
+        let passport = passports
+            .get(&passport_id)
+            .ok_or_else(|| passport_not_found(passport_id))?;
+        if !passport.is_authorized(user) {
+            return Err(Error::Unauthorized(
+                "unauthorized access to passport".to_string(),
+            ));
+        }
+        Ok(())
     })
 }
 
+fn passport_not_found(passport_id: PassportId) -> Error {
+    Error::NotFound(format!("passport with ID {passport_id} does not exist"))
+}
+
 // ===== SYNTHETIC DATA GENERATION FUNCTIONS =====
 
-/// Creates a new synthetic data generation job
+/// Creates a new synthetic data generation job and charges the epsilon of its
+/// privacy level to the dataset's privacy budget. Without a seed in `request`,
+/// one is drawn from the management canister's `raw_rand`.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      the privacy level is not "low", "medium" or "high"
+///      `num_records` exceeds [MAX_SYNTHETIC_RECORDS]
+///      the call to `raw_rand` fails
+///      the dataset does not exist
+///      [caller] is not the dataset's owner and not an editor or co-owner of it
+///      the epsilon exceeds the remaining privacy budget of the dataset
 #[update]
-fn create_synthetic_job(request: SyntheticDataRequest) -> Result<String, String> {
-    let owner = caller().to_string();
-    
-    // Verify the dataset exists and user has access
-    let dataset_exists = NOTES.with_borrow(|notes| {
-        notes.iter().any(|(_, note)| {
-            note.id.to_string() == request.dataset_id && note.is_authorized(&owner)
-        })
-    });
-    
-    if !dataset_exists {
-        return Err("Dataset not found or access denied".to_string());
-    }
-    
-    let job_id = format!("job_{}_{}", ic_cdk::api::time(), owner.chars().take(8).collect::<String>());
-    let current_time = ic_cdk::api::time();
-    
+async fn create_synthetic_job(request: SyntheticDataRequest) -> Result<SyntheticJobId, Error> {
+    let owner = caller()?.to_string();
+    let epsilon = privacy_epsilon(&request.privacy_level)?;
+    if request.num_records > MAX_SYNTHETIC_RECORDS {
+        return Err(Error::QuotaExceeded(format!(
+            "synthetic jobs generate at most {MAX_SYNTHETIC_RECORDS} records"
+        )));
+    }
+    // Drawn before any check that reads canister state, so that no state can
+    // change between those checks and the insertion of the job. Only the
+    // request itself is validated before the call.
+    let seed = match request.seed {
+        Some(seed) => seed,
+        None => random_seed().await?,
+    };
+
+    let note_id = dataset_note_id(&request.dataset_id)?;
+    editable_note(note_id, &owner)?;
+    charge_privacy_budget(note_id, &request.privacy_level, epsilon)?;
+
+    let job_id = new_job_id();
+    let current_time = system::time();
+
     let job = SyntheticDataJob {
-        job_id: job_id.clone(),
+        job_id,
         dataset_id: request.dataset_id.clone(),
         owner,
-        status: "pending".to_string(),
+        status: JobStatus::Pending,
         progress: 0,
         created_at: current_time,
         completed_at: None,
         result_dataset_id: None,
         error_message: None,
         settings: request,
+        seed: Some(seed),
+        epsilon_spent: None,
+        leakage_report: None,
+        legacy_id: None,
+        source_version: latest_version_number(note_id),
     };
-    
-    SYNTHETIC_JOBS.with_borrow_mut(|jobs| {
-        jobs.insert(job_id.clone(), job);
-    });
-    
+
+    add_synthetic_job(job);
+    schedule_synthetic_jobs();
+
     Ok(job_id)
 }
 
+/// Sets the privacy budget cap of dataset `note_id`, or of the dataset it was
+/// derived from. A cap below the budget already spent prevents further
+/// synthetic jobs on the dataset.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      `cap` is negative or not finite
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner
+#[update]
+fn set_privacy_budget(note_id: NoteId, cap: f64) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    if !cap.is_finite() || cap < 0.0 {
+        return Err(Error::InvalidInput(format!("invalid privacy budget {cap}")));
+    }
+    owned_note(note_id, &user_str)?;
+    let budget_id = budget_note_id(note_id);
+    PRIVACY_BUDGETS.with_borrow_mut(|budgets| {
+        let mut budget = budgets.get(&budget_id).unwrap_or_default();
+        budget.cap = cap;
+        budgets.insert(budget_id, budget);
+    });
+    Ok(())
+}
+
+/// Returns the privacy budget of dataset `note_id`, which is that of the
+/// dataset it was derived from, if any.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      note with id `note_id` does not exist
+///      [caller] is not the note's owner and not a user with whom the note is shared
+#[update]
+fn get_privacy_budget(note_id: NoteId) -> Result<PrivacyBudget, Error> {
+    let user_str = caller()?.to_string();
+    authorized_note(note_id, &user_str)?;
+    Ok(PRIVACY_BUDGETS
+        .with_borrow(|budgets| budgets.get(&budget_note_id(note_id)))
+        .unwrap_or_default())
+}
+
+/// ID of the dataset whose privacy budget releases from `note_id` draw on.
+/// Charges `epsilon`, spent at privacy level `privacy_level`, to the privacy
+/// budget of dataset `note_id`.
+fn charge_privacy_budget(note_id: NoteId, privacy_level: &str, epsilon: f64) -> Result<(), Error> {
+    let budget_id = budget_note_id(note_id);
+    let mut budget = PRIVACY_BUDGETS
+        .with_borrow(|budgets| budgets.get(&budget_id))
+        .unwrap_or_default();
+    if budget.spent + epsilon > budget.cap {
+        return Err(Error::QuotaExceeded(format!(
+            "privacy level {privacy_level:?} needs epsilon {epsilon}, but only {} of the privacy budget of dataset {note_id} remains",
+            (budget.cap - budget.spent).max(0.0)
+        )));
+    }
+    budget.spent += epsilon;
+    PRIVACY_BUDGETS.with_borrow_mut(|budgets| budgets.insert(budget_id, budget));
+    Ok(())
+}
+
+fn budget_note_id(note_id: NoteId) -> NoteId {
+    BUDGET_SOURCES
+        .with_borrow(|sources| sources.get(&note_id))
+        .unwrap_or(note_id)
+}
+
 /// Gets the status of a synthetic data generation job
 #[update]
-fn get_synthetic_job_status(job_id: String) -> Result<SyntheticDataJob, String> {
-    let user_str = caller().to_string();
-    
+fn get_synthetic_job_status(job_id: SyntheticJobId) -> Result<SyntheticDataJob, Error> {
+    let user_str = caller()?.to_string();
+
     SYNTHETIC_JOBS.with_borrow(|jobs| {
         if let Some(job) = jobs.get(&job_id) {
             if job.is_authorized(&user_str) {
                 Ok(job)
             } else {
-                Err("Unauthorized access to job".to_string())
+                Err(Error::Unauthorized(
+                    "Unauthorized access to job".to_string(),
+                ))
             }
         } else {
-            Err("Job not found".to_string())
+            Err(Error::NotFound("Job not found".to_string()))
         }
     })
 }
 
-/// Updates the progress of a synthetic data generation job (internal function)
+/// Returns the fidelity report of completed synthetic job `job_id`.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      job with id `job_id` does not exist
+///      [caller] is not the job's owner
+///      the job has not completed
 #[update]
-fn update_synthetic_job_progress(job_id: String, progress: u8, status: String) -> Result<(), String> {
-    let user_str = caller().to_string();
-    
-    SYNTHETIC_JOBS.with_borrow_mut(|jobs| {
-        if let Some(mut job) = jobs.get(&job_id) {
-            if job.is_authorized(&user_str) {
-                job.progress = progress;
-                job.status = status;
-                if progress >= 100 {
-                    job.completed_at = Some(ic_cdk::api::time());
-                    job.status = "completed".to_string();
-                    
-                    // Create a synthetic dataset (mock implementation)
-                    let synthetic_dataset_id = format!("synthetic_{}_{}", job.dataset_id, ic_cdk::api::time());
-                    job.result_dataset_id = Some(synthetic_dataset_id.clone());
-                    
-                    // Create the synthetic dataset as a new note
-                    let _ = create_synthetic_dataset(&job, &synthetic_dataset_id);
-                }
-                jobs.insert(job_id, job);
-                Ok(())
+fn get_synthetic_quality_report(job_id: SyntheticJobId) -> Result<QualityReport, Error> {
+    let user_str = caller()?.to_string();
+    owned_job(job_id, &user_str)?;
+    QUALITY_REPORTS
+        .with_borrow(|reports| reports.get(&job_id))
+        .ok_or_else(|| Error::NotFound(format!("job {job_id} has no quality report")))
+}
+
+/// Stops unfinished synthetic job `job_id`. The privacy budget charged for
+/// the job is not refunded.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      job with id `job_id` does not exist
+///      [caller] is not the job's owner
+///      the job has already finished
+#[update]
+fn cancel_synthetic_job(job_id: SyntheticJobId) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    let mut job = owned_job(job_id, &user_str)?;
+    job.transition(JobStatus::Cancelled)?;
+    dequeue_job(job_id);
+    SYNTHETIC_JOBS.with_borrow_mut(|jobs| jobs.insert(job_id, job));
+    Ok(())
+}
+
+/// Starts a new job with the settings and seed of failed or cancelled
+/// synthetic job `job_id` and returns its ID. As the same seed on the same
+/// source version yields the same output, the retry is not charged to the
+/// privacy budget again, except for jobs created before source versions were
+/// recorded: those read the dataset's current content, which may have changed.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      job with id `job_id` does not exist
+///      [caller] is not the job's owner
+///      the job has not failed and was not cancelled
+///      [caller] is no longer the dataset's owner or an editor or co-owner of it
+///      the job has no source version and exceeds the remaining privacy budget
+#[update]
+fn retry_synthetic_job(job_id: SyntheticJobId) -> Result<SyntheticJobId, Error> {
+    let user_str = caller()?.to_string();
+    let job = owned_job(job_id, &user_str)?;
+    if !matches!(job.status, JobStatus::Failed | JobStatus::Cancelled) {
+        return Err(Error::InvalidInput(format!(
+            "job {job_id} is {:?}, only failed or cancelled jobs can be retried",
+            job.status
+        )));
+    }
+    let note_id = dataset_note_id(&job.dataset_id)?;
+    editable_note(note_id, &user_str)?;
+    if job.source_version.is_none() {
+        let privacy_level = &job.settings.privacy_level;
+        charge_privacy_budget(note_id, privacy_level, privacy_epsilon(privacy_level)?)?;
+    }
+
+    let retry_id = new_job_id();
+    let retry = SyntheticDataJob {
+        job_id: retry_id,
+        dataset_id: job.dataset_id,
+        owner: job.owner,
+        status: JobStatus::Pending,
+        progress: 0,
+        created_at: system::time(),
+        completed_at: None,
+        result_dataset_id: None,
+        error_message: None,
+        settings: job.settings,
+        seed: job.seed,
+        epsilon_spent: None,
+        leakage_report: None,
+        legacy_id: None,
+        source_version: job.source_version,
+    };
+    add_synthetic_job(retry);
+    schedule_synthetic_jobs();
+    Ok(retry_id)
+}
+
+/// Deletes synthetic job `job_id` and its quality report, stopping it if it
+/// is unfinished. If `delete_result` is set, the dataset the job generated
+/// is deleted as well.
+///
+/// Errors:
+///      [caller] is the anonymous identity
+///      job with id `job_id` does not exist
+///      [caller] is not the job's owner
+///      `delete_result` is set and [caller] no longer owns the generated dataset
+#[update]
+fn delete_synthetic_job(job_id: SyntheticJobId, delete_result: bool) -> Result<(), Error> {
+    let user_str = caller()?.to_string();
+    let job = owned_job(job_id, &user_str)?;
+    let result_note = match result_note_id(&job) {
+        Some(note_id) if delete_result => match owned_note(note_id, &user_str) {
+            Ok(_) => Some(note_id),
+            // Deleted already.
+            Err(Error::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        },
+        _ => None,
+    };
+
+    dequeue_job(job_id);
+    QUALITY_REPORTS.with_borrow_mut(|reports| reports.remove(&job_id));
+    remove_synthetic_job(&job);
+    if let Some(note_id) = result_note {
+        remove_note(note_id);
+    }
+    Ok(())
+}
+
+/// Returns synthetic job `job_id` if `user` owns it.
+fn owned_job(job_id: SyntheticJobId, user: &PrincipalName) -> Result<SyntheticDataJob, Error> {
+    let job = SYNTHETIC_JOBS
+        .with_borrow(|jobs| jobs.get(&job_id))
+        .ok_or_else(|| Error::NotFound("Job not found".to_string()))?;
+    if !job.is_authorized(user) {
+        return Err(Error::Unauthorized(
+            "Unauthorized access to job".to_string(),
+        ));
+    }
+    Ok(job)
+}
+
+/// ID of the note holding the output of `job`, if it has been stored. Jobs
+/// completed before results were linked to notes have none.
+fn result_note_id(job: &SyntheticDataJob) -> Option<NoteId> {
+    job.result_dataset_id.as_ref()?.parse().ok()
+}
+
+/// Stores new synthetic job `job` and adds it to its owner's jobs.
+fn add_synthetic_job(job: SyntheticDataJob) {
+    index_synthetic_job(&job);
+    pin_source_version(&job);
+    if !job.status.is_finished() {
+        UNFINISHED_JOBS.with_borrow_mut(|queue| queue.insert(job.job_id, 0));
+    }
+    SYNTHETIC_JOBS.with_borrow_mut(|jobs| jobs.insert(job.job_id, job));
+}
+
+fn index_synthetic_job(job: &SyntheticDataJob) {
+    JOB_OWNERS.with_borrow_mut(|owners| {
+        let mut owner_jobs = owners.get(&job.owner).unwrap_or_default();
+        owner_jobs.ids.push(job.job_id);
+        owners.insert(job.owner.clone(), owner_jobs);
+    });
+}
+
+fn remove_synthetic_job(job: &SyntheticDataJob) {
+    JOB_OWNERS.with_borrow_mut(|owners| {
+        if let Some(mut owner_jobs) = owners.get(&job.owner) {
+            owner_jobs.ids.retain(|&id| id != job.job_id);
+            if !owner_jobs.ids.is_empty() {
+                owners.insert(job.owner.clone(), owner_jobs);
             } else {
-                Err("Unauthorized access to job".to_string())
+                owners.remove(&job.owner);
             }
-        } else {
-            Err("Job not found".to_string())
         }
+    });
+    unpin_source_version(job);
+    SYNTHETIC_JOBS.with_borrow_mut(|jobs| jobs.remove(&job.job_id));
+}
+
+fn new_job_id() -> SyntheticJobId {
+    NEXT_JOB_ID.with_borrow_mut(|next_job_id| {
+        let id = *next_job_id.get();
+        let id_plus_one = id
+            .checked_add(1)
+            .expect("failed to increase NEXT_JOB_ID: reached the maximum");
+        next_job_id
+            .set(id_plus_one)
+            .unwrap_or_else(|_e| ic_cdk::trap("failed to set NEXT_JOB_ID"));
+        id
     })
 }
 
-/// Gets all synthetic jobs for the current user
+/// Gets a page of the synthetic jobs of the current user
 #[update]
-fn get_my_synthetic_jobs() -> Vec<SyntheticDataJob> {
-    let user_str = caller().to_string();
-    
-    SYNTHETIC_JOBS.with_borrow(|jobs| {
-        jobs.iter()
-            .filter_map(|(_, job)| {
-                if job.is_authorized(&user_str) {
-                    Some(job)
-                } else {
-                    None
-                }
-            })
-            .collect()
-    })
+fn get_my_synthetic_jobs(page: PageRequest) -> Result<Page<SyntheticDataJob>, Error> {
+    let user_str = caller()?.to_string();
+    let job_ids = JOB_OWNERS
+        .with_borrow(|owners| owners.get(&user_str))
+        .unwrap_or_default()
+        .ids;
+    SYNTHETIC_JOBS.with_borrow(|jobs| paginate(job_ids, &page, |id| Ok(jobs.get(&id))))
+}
+
+/// A synthetic job being processed, one [JobPhase] after the other. Phases
+/// advance in steps that each do a bounded amount of work, so that a slice
+/// can stop after any step.
+struct JobRun {
+    phase: JobPhase,
+    /// Set once parsed.
+    source: csv::Table,
+    rng: rng::Rng,
+    /// Rows generated so far.
+    output: csv::Table,
+    epsilon: f64,
+}
+
+enum JobPhase {
+    /// Reading the chunks of the source dataset, one per step.
+    Reading {
+        note_id: NoteId,
+        content: ChunkedContent,
+        next_chunk: u32,
+        bytes: Vec<u8>,
+    },
+    /// Parsing the source dataset, [PARSED_BYTES_PER_STEP] per step.
+    Parsing {
+        bytes: Vec<u8>,
+        reader: csv::TableReader,
+    },
+    Fitting(synth::ModelFit),
+    /// Generating one row per step.
+    Generating(synth::TableModel),
+    EvaluatingLeakage(leakage::Evaluation),
+    EvaluatingQuality(quality::Evaluation),
+    /// Writing one row per step, and finally storing the dataset.
+    Storing {
+        writer: csv::Writer,
+        text: DatasetText,
+        next_row: usize,
+    },
+    Completed,
+}
+
+/// Makes sure that a timer processes the unfinished synthetic jobs.
+fn schedule_synthetic_jobs() {
+    if JOB_TIMER.with(Cell::get).is_none() {
+        set_job_timer(Duration::ZERO);
+    }
+}
+
+/// Sets the timer dispatching the next job slice after `delay`, replacing
+/// any earlier one.
+fn set_job_timer(delay: Duration) {
+    if let Some(timer) = JOB_TIMER.with(Cell::take) {
+        system::clear_timer(timer);
+    }
+    let timer_id = system::set_timer(delay, || {
+        JOB_TIMER.with(|timer| timer.set(None));
+        dispatch_synthetic_job();
+    });
+    JOB_TIMER.with(|timer| timer.set(Some(timer_id)));
 }
 
-/// Creates a synthetic dataset from a completed job (internal function)
-fn create_synthetic_dataset(job: &SyntheticDataJob, _synthetic_dataset_id: &str) -> Result<NoteId, String> {
-    let owner = job.owner.clone();
-    
-    // Generate enhanced synthetic data based on original dataset
-    let synthetic_data = generate_enhanced_synthetic_data(&job.settings)?;
-    
-    // Create encrypted note with synthetic data
-    let synthetic_note = EncryptedNote {
-        id: 0, // Will be set by add_note
-        encrypted_text: synthetic_data,
-        owner: owner.clone(),
-        users: vec![owner],
+/// Starts a slice of the oldest unfinished synthetic job. The slice runs in a
+/// message of its own: before it starts, this message counts the attempt and
+/// arms a timer that dispatches again after [SLICE_RETRY_DELAY], so that a
+/// trapping slice is retried and its job fails after [MAX_SLICE_ATTEMPTS].
+fn dispatch_synthetic_job() {
+    let Some((job_id, attempts)) = UNFINISHED_JOBS.with_borrow(|queue| queue.first_key_value())
+    else {
+        return;
     };
-    
-    // Store the synthetic dataset
-    NOTES.with_borrow_mut(|notes| {
-        let next_note_id = NEXT_NOTE_ID.with_borrow_mut(|next_id| {
-            let id = next_id.get().clone();
-            let id_plus_one = id
-                .checked_add(1)
-                .unwrap_or_else(|| ic_cdk::trap("note id overflow"));
-            next_id
-                .set(id_plus_one)
-                .unwrap_or_else(|_e| ic_cdk::trap("failed to set NEXT_NOTE_ID"));
-            id
-        });
-        
-        let mut note_with_id = synthetic_note;
-        note_with_id.id = next_note_id;
-        
-        notes.insert(next_note_id, note_with_id);
-        
-        Ok(next_note_id)
-    })
+    if attempts >= MAX_SLICE_ATTEMPTS {
+        if let Some(mut job) = SYNTHETIC_JOBS.with_borrow(|jobs| jobs.get(&job_id)) {
+            fail_job(
+                &mut job,
+                format!("processing failed {attempts} times in a row"),
+            );
+            SYNTHETIC_JOBS.with_borrow_mut(|jobs| jobs.insert(job_id, job));
+        }
+        dequeue_job(job_id);
+        set_job_timer(Duration::ZERO);
+        return;
+    }
+    UNFINISHED_JOBS.with_borrow_mut(|queue| queue.insert(job_id, attempts + 1));
+    set_job_timer(SLICE_RETRY_DELAY);
+    system::set_timer(Duration::ZERO, move || process_job_slice(job_id));
 }
 
-/// Enhanced synthetic data generator that analyzes original dataset
-fn generate_enhanced_synthetic_data(settings: &SyntheticDataRequest) -> Result<String, String> {
-    // Get original dataset to analyze structure
-    let original_data = NOTES.with_borrow(|notes| {
-        for (_, note) in notes.iter() {
-            if note.id.to_string() == settings.dataset_id {
-                return Ok(note.encrypted_text.clone());
-            }
+/// Runs one slice of job `job_id` and dispatches the next slice right away.
+fn process_job_slice(job_id: SyntheticJobId) {
+    // Cancelled or deleted jobs are no longer queued.
+    let job = SYNTHETIC_JOBS
+        .with_borrow(|jobs| jobs.get(&job_id))
+        .filter(|job| !job.status.is_finished());
+    if let Some(mut job) = job {
+        if let Err(e) = run_job_slice(&mut job) {
+            fail_job(&mut job, e.to_string());
         }
-        Err("Dataset not found".to_string())
-    })?;
+        if job.status.is_finished() {
+            dequeue_job(job_id);
+        } else {
+            UNFINISHED_JOBS.with_borrow_mut(|queue| queue.insert(job_id, 0));
+        }
+        SYNTHETIC_JOBS.with_borrow_mut(|jobs| jobs.insert(job_id, job));
+    }
+    set_job_timer(Duration::ZERO);
+}
 
-    // Analyze original data structure
-    let lines: Vec<&str> = original_data.lines().collect();
-    if lines.is_empty() {
-        return Err("Empty dataset".to_string());
-    }
-
-    // Use first line as headers, analyze structure
-    let headers = lines[0];
-    let _header_count = headers.split(',').count();
-    
-    // Generate synthetic data based on detected structure
-    if headers.contains("age") && headers.contains("diagnosis") {
-        generate_medical_synthetic_data(settings, headers)
-    } else if headers.contains("patient") || headers.contains("medical") {
-        generate_medical_synthetic_data(settings, headers)
-    } else {
-        generate_generic_synthetic_data(settings, headers)
+fn fail_job(job: &mut SyntheticDataJob, message: String) {
+    JOB_RUNS.with_borrow_mut(|runs| runs.remove(&job.job_id));
+    job.error_message = Some(message);
+    // Unfinished jobs can always fail.
+    let _ = job.transition(JobStatus::Failed);
+}
+
+/// Stops processing job `job_id`, discarding its run.
+fn dequeue_job(job_id: SyntheticJobId) {
+    JOB_RUNS.with_borrow_mut(|runs| runs.remove(&job_id));
+    UNFINISHED_JOBS.with_borrow_mut(|queue| queue.remove(&job_id));
+}
+
+/// Advances `job` by as many steps as fit in [SLICE_INSTRUCTIONS].
+fn run_job_slice(job: &mut SyntheticDataJob) -> Result<(), Error> {
+    let mut run = match JOB_RUNS.with_borrow_mut(|runs| runs.remove(&job.job_id)) {
+        Some(run) => run,
+        // Jobs interrupted by an upgrade may already be running and start over.
+        None => start_job_run(job)?,
+    };
+    while system::instruction_counter() < SLICE_INSTRUCTIONS {
+        if advance_job(job, &mut run)? {
+            return Ok(());
+        }
     }
+    JOB_RUNS.with_borrow_mut(|runs| runs.insert(job.job_id, run));
+    Ok(())
 }
 
-fn generate_medical_synthetic_data(settings: &SyntheticDataRequest, headers: &str) -> Result<String, String> {
-    let mut csv_lines = vec![headers.to_string()];
-    
-    // Medical data patterns based on privacy level
-    let privacy_multiplier = match settings.privacy_level.as_str() {
-        "high" => 3,
-        "medium" => 2,
-        _ => 1,
+/// Prepares reading the source dataset of `job`, as of the version the job
+/// pinned.
+fn start_job_run(job: &SyntheticDataJob) -> Result<JobRun, Error> {
+    let epsilon = privacy_epsilon(&job.settings.privacy_level)?;
+    let seed = job.seed.unwrap_or_else(|| legacy_job_seed(job));
+    let note_id = dataset_note_id(&job.settings.dataset_id)?;
+    let note = NOTES
+        .with_borrow(|notes| notes.get(&note_id))
+        .ok_or_else(|| Error::NotFound("Dataset not found".to_string()))?;
+    let (text, chunked_content) = match job.source_version {
+        Some(version) => {
+            let source = note_version(note_id, version)
+                .ok_or_else(|| version_not_found(note_id, version))?;
+            (version_text(&note, version), source.chunked_content)
+        }
+        None => (note.encrypted_text, note.chunked_content),
     };
-    
-    for i in 1..=settings.num_records {
-        let mut fields = Vec::new();
-        
-        // Generate fields based on headers
-        for header in headers.split(',') {
-            let field = match header.trim().to_lowercase().as_str() {
-                "id" => format!("SYN_{:06}", i),
-                "patient_id" => format!("P_{:08}", i * privacy_multiplier),
-                "age" => format!("{}", 18 + (i * 7) % 80),
-                "gender" | "sex" => if i % 2 == 0 { "M".to_string() } else { "F".to_string() },
-                "diagnosis" => format!("ICD10_{}", 1000 + (i * 13) % 1000),
-                "treatment" => format!("TX_{}", 100 + (i * 17) % 100),
-                "outcome" => match i % 5 {
-                    0 => "Improved",
-                    1 => "Stable", 
-                    2 => "Recovered",
-                    3 => "Ongoing",
-                    _ => "Discharged"
-                }.to_string(),
-                "date" | "created_date" | "admission_date" => {
-                    format!("2024-{:02}-{:02}", 1 + (i % 12), 1 + (i % 28))
-                },
-                "weight" => format!("{:.1}", 50.0 + (i as f64 * 0.7) % 100.0),
-                "height" => format!("{}", 150 + (i * 3) % 50),
-                "blood_pressure" => format!("{}/{}", 90 + (i * 2) % 60, 60 + (i * 1) % 40),
-                "temperature" => format!("{:.1}", 36.0 + (i as f64 * 0.1) % 3.0),
-                _ => format!("VAL_{}", i % 1000),
-            };
-            fields.push(field);
+    let phase = match chunked_content {
+        Some(content) => {
+            check_readable(note_id, &content)?;
+            JobPhase::Reading {
+                note_id,
+                bytes: Vec::with_capacity(content.total_bytes as usize),
+                content,
+                next_chunk: 0,
+            }
         }
-        
-        csv_lines.push(fields.join(","));
+        None => parsing(text.into_bytes()),
+    };
+    Ok(JobRun {
+        phase,
+        source: csv::Table::default(),
+        rng: rng::Rng::seed_from_u64(seed),
+        output: csv::Table::default(),
+        epsilon,
+    })
+}
+
+fn parsing(bytes: Vec<u8>) -> JobPhase {
+    JobPhase::Parsing {
+        reader: csv::TableReader::new(&bytes),
+        bytes,
     }
-    
-    Ok(csv_lines.join("\n"))
 }
 
-fn generate_generic_synthetic_data(settings: &SyntheticDataRequest, headers: &str) -> Result<String, String> {
-    let mut csv_lines = vec![headers.to_string()];
-    
-    for i in 1..=settings.num_records {
-        let mut fields = Vec::new();
-        
-        for (idx, _header) in headers.split(',').enumerate() {
-            let field = match idx % 4 {
-                0 => format!("ID_{:06}", i),
-                1 => format!("{}", 10 + (i * 7) % 90),
-                2 => format!("CAT_{}", (i * 13) % 10),
-                _ => format!("VAL_{}", (i * 17) % 1000),
-            };
-            fields.push(field);
+/// Does the next step of `run`. Returns true once `job` is completed.
+fn advance_job(job: &mut SyntheticDataJob, run: &mut JobRun) -> Result<bool, Error> {
+    let phase = std::mem::replace(&mut run.phase, JobPhase::Completed);
+    run.phase = match phase {
+        JobPhase::Reading {
+            note_id,
+            content,
+            next_chunk,
+            mut bytes,
+        } => {
+            if next_chunk < content.chunk_count {
+                let chunk = CONTENT_CHUNKS
+                    .with_borrow(|chunks| chunks.get(&(content.upload_id, next_chunk)))
+                    .ok_or_else(|| {
+                        Error::Internal(format!("missing chunk {next_chunk} of note {note_id}"))
+                    })?;
+                bytes.extend_from_slice(&chunk);
+                JobPhase::Reading {
+                    note_id,
+                    content,
+                    next_chunk: next_chunk + 1,
+                    bytes,
+                }
+            } else {
+                parsing(bytes)
+            }
         }
-        
-        csv_lines.push(fields.join(","));
-    }
-    
-    Ok(csv_lines.join("\n"))
-}
-
-fn generate_mock_synthetic_data(settings: &SyntheticDataRequest) -> String {
-    let mut csv_lines = vec!["id,age,gender,diagnosis,treatment,outcome,created_date".to_string()];
-    
-    for i in 1..=settings.num_records {
-        let age = 18 + (i * 7) % 80; // Pseudo-random age
-        let gender = if i % 2 == 0 { "M" } else { "F" };
-        let diagnosis = format!("ICD_{}", 1000 + (i * 13) % 1000);
-        let treatment = format!("TX_{}", 100 + (i * 17) % 100);
-        let outcome = if i % 5 == 0 { "Improved" } else { "Stable" };
-        let date = format!("2024-{:02}-{:02}", 1 + (i % 12), 1 + (i % 28));
-        
-        let line = format!("SYN_{:06},{},{},{},{},{},{}", 
-            i, age, gender, diagnosis, treatment, outcome, date);
-        csv_lines.push(line);
+        JobPhase::Parsing { bytes, mut reader } => {
+            if !reader.read(&bytes, PARSED_BYTES_PER_STEP) {
+                JobPhase::Parsing { bytes, reader }
+            } else {
+                run.source = reader.finish();
+                if run.source.headers.is_empty() || run.source.rows.is_empty() {
+                    return Err(Error::InvalidInput("Empty dataset".to_string()));
+                }
+                let options = synth::FitOptions {
+                    mask_pii: job.settings.hipaa_compliant,
+                    preserve_correlations: job.settings.preserve_correlations,
+                    epsilon: Some(run.epsilon),
+                };
+                JobPhase::Fitting(synth::ModelFit::new(&run.source, &options))
+            }
+        }
+        JobPhase::Fitting(mut fit) => {
+            if !fit.step(&run.source, &mut run.rng) {
+                JobPhase::Fitting(fit)
+            } else {
+                let model = fit.finish();
+                run.output = csv::Table {
+                    delimiter: model.delimiter,
+                    headers: model.headers().map(str::to_string).collect(),
+                    ..csv::Table::default()
+                };
+                if job.status == JobStatus::Pending {
+                    job.transition(JobStatus::Running)?;
+                }
+                job.progress = FITTED_PROGRESS;
+                JobPhase::Generating(model)
+            }
+        }
+        JobPhase::Generating(model) => {
+            let total = job.settings.num_records as usize;
+            if run.output.rows.len() < total {
+                let row = model.sample_row(run.output.rows.len(), &mut run.rng);
+                run.output.rows.push(row);
+            }
+            let generated = run.output.rows.len() as u64
+                * u64::from(GENERATED_PROGRESS - FITTED_PROGRESS)
+                / total.max(1) as u64;
+            job.progress = FITTED_PROGRESS + generated as u8;
+            if run.output.rows.len() < total {
+                JobPhase::Generating(model)
+            } else {
+                JobPhase::EvaluatingLeakage(leakage::Evaluation::default())
+            }
+        }
+        JobPhase::EvaluatingLeakage(mut evaluation) => {
+            if !evaluation.step(&run.source, &run.output) {
+                JobPhase::EvaluatingLeakage(evaluation)
+            } else {
+                let report = evaluation.finish();
+                job.epsilon_spent = Some(run.epsilon);
+                let violations = job
+                    .settings
+                    .leakage_thresholds
+                    .as_ref()
+                    .map(|thresholds| thresholds.violations(&report))
+                    .unwrap_or_default();
+                job.leakage_report = Some(report);
+                if !violations.is_empty() {
+                    return Err(Error::InvalidInput(format!(
+                        "privacy leakage thresholds exceeded: {}",
+                        violations.join("; ")
+                    )));
+                }
+                job.progress = GENERATED_PROGRESS + 2;
+                JobPhase::EvaluatingQuality(quality::Evaluation::default())
+            }
+        }
+        JobPhase::EvaluatingQuality(mut evaluation) => {
+            if !evaluation.step(&run.source, &run.output) {
+                JobPhase::EvaluatingQuality(evaluation)
+            } else {
+                let quality = evaluation.finish(system::time());
+                QUALITY_REPORTS.with_borrow_mut(|reports| reports.insert(job.job_id, quality));
+                job.progress = GENERATED_PROGRESS + 4;
+                let mut writer = csv::Writer::new(run.output.delimiter);
+                writer.write_record(&run.output.headers);
+                JobPhase::Storing {
+                    writer,
+                    text: DatasetText::default(),
+                    next_row: 0,
+                }
+            }
+        }
+        JobPhase::Storing {
+            mut writer,
+            mut text,
+            next_row,
+        } => {
+            if let Some(row) = run.output.rows.get(next_row) {
+                writer.write_record(row);
+                text.push(&writer.take());
+                JobPhase::Storing {
+                    writer,
+                    text,
+                    next_row: next_row + 1,
+                }
+            } else {
+                text.push(&writer.finish());
+                let note_id = store_job_output(job, text)?;
+                job.transition(JobStatus::Completed)?;
+                unpin_source_version(job);
+                job.progress = 100;
+                job.result_dataset_id = Some(note_id.to_string());
+                JobPhase::Completed
+            }
+        }
+        JobPhase::Completed => JobPhase::Completed,
+    };
+    Ok(matches!(run.phase, JobPhase::Completed))
+}
+
+/// Stores the output `text` of `job` as a new synthetic dataset.
+fn store_job_output(job: &SyntheticDataJob, text: DatasetText) -> Result<NoteId, Error> {
+    let note_id = text.insert(job.owner.clone())?;
+    DATASET_METADATA.with_borrow_mut(|all| {
+        if let Some(mut metadata) = all.get(&note_id) {
+            metadata.kind = DatasetKind::Synthetic;
+            metadata.title = format!("Synthetic data from dataset {}", job.dataset_id);
+            all.insert(note_id, metadata);
+        }
+    });
+    Ok(note_id)
+}
+
+fn dataset_note_id(dataset_id: &str) -> Result<NoteId, Error> {
+    dataset_id
+        .parse()
+        .map_err(|_| Error::InvalidInput(format!("invalid dataset ID {dataset_id}")))
+}
+
+fn privacy_epsilon(privacy_level: &str) -> Result<f64, Error> {
+    synth::privacy_epsilon(privacy_level).ok_or_else(|| {
+        Error::InvalidInput(format!(
+            "unknown privacy level {privacy_level:?}, expected \"low\", \"medium\" or \"high\""
+        ))
+    })
+}
+
+async fn random_seed() -> Result<u64, Error> {
+    let bytes = ic_cdk::management_canister::raw_rand()
+        .await
+        .map_err(|e| Error::Internal(format!("call to raw_rand failed: {e}")))?;
+    let mut seed = [0; 8];
+    seed.copy_from_slice(&bytes[..8]);
+    Ok(u64::from_be_bytes(seed))
+}
+
+/// Derives the sampling seed of a job created before seeds were recorded
+/// from its string ID and creation time.
+fn legacy_job_seed(job: &SyntheticDataJob) -> u64 {
+    let mut hasher = Sha256::new();
+    let legacy_id = job.legacy_id.clone();
+    let id = legacy_id.unwrap_or_else(|| job.job_id.to_string());
+    hasher.update(id.as_bytes());
+    hasher.update(job.created_at.to_be_bytes());
+    let mut seed = [0; 8];
+    seed.copy_from_slice(&hasher.finalize()[..8]);
+    u64::from_be_bytes(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call_as(n: u8) {
+        system::set_caller(Principal::from_slice(&[n; 10]));
+    }
+
+    fn page(cursor: Option<Cursor>, limit: u32) -> PageRequest {
+        PageRequest {
+            cursor,
+            limit: Some(limit),
+        }
+    }
+
+    fn listed_ids(listing: &Page<NoteListing>) -> Vec<NoteId> {
+        listing.items.iter().map(|note| note.id).collect()
+    }
+
+    #[test]
+    fn notes_are_paged_by_cursor() {
+        call_as(1);
+        let ids: Vec<NoteId> = (0..5).map(|_| create_note().unwrap()).collect();
+        call_as(2);
+        create_note().unwrap();
+
+        call_as(1);
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let listing = get_notes(page(cursor, 2), false).unwrap();
+            assert!(listing.items.len() <= 2);
+            listed.extend(listed_ids(&listing));
+            match listing.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(listed, ids);
+
+        let rest = get_notes(page(Some(ids[1].to_string()), 10), false).unwrap();
+        assert_eq!(listed_ids(&rest), ids[2..]);
+        assert_eq!(rest.next_cursor, None);
+        assert!(matches!(
+            get_notes(page(Some("x".to_string()), 10), false),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_content_is_left_out_of_listings() {
+        call_as(1);
+        let small = create_note().unwrap();
+        update_note(small, "small".to_string()).unwrap();
+        let large = create_note().unwrap();
+        let text = "é".repeat(MAX_NOTE_CHARS - 1);
+        update_note(large, text.clone()).unwrap();
+
+        // The large note does not fit next to the small one, nor on its own.
+        let first = get_notes(PageRequest::default(), true).unwrap();
+        assert_eq!(listed_ids(&first), [small]);
+        assert_eq!(first.items[0].encrypted_text.as_deref(), Some("small"));
+        let second = get_notes(page(first.next_cursor, 10), true).unwrap();
+        assert_eq!(listed_ids(&second), [large]);
+        let omitted = &second.items[0];
+        assert_eq!(omitted.encrypted_text, None);
+        let chunks = omitted.omitted_content_chunks.unwrap();
+        assert_eq!(chunks, 2);
+        let bytes: Vec<u8> = (0..chunks)
+            .flat_map(|index| get_note_chunk(large, index).unwrap())
+            .collect();
+        assert_eq!(bytes, text.as_bytes());
+        assert!(get_note_chunk(large, chunks).is_err());
+    }
+
+    fn principal(n: u8) -> PrincipalName {
+        Principal::from_slice(&[n; 10]).to_text()
+    }
+
+    #[test]
+    fn share_roles_limit_access() {
+        call_as(1);
+        let note_id = create_note().unwrap();
+        add_user(note_id, principal(2), ShareRole::Viewer, None).unwrap();
+        add_user(note_id, principal(3), ShareRole::Editor, None).unwrap();
+        add_user(note_id, principal(4), ShareRole::CoOwner, None).unwrap();
+
+        call_as(2);
+        assert!(get_note_chunk(note_id, 0).is_ok());
+        assert!(matches!(
+            update_note(note_id, "x".to_string()),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(set_dataset_metadata(note_id, "t".to_string(), String::new()).is_err());
+        assert!(editable_note(note_id, &principal(2)).is_err());
+        assert!(add_user(note_id, principal(5), ShareRole::Viewer, None).is_err());
+
+        call_as(3);
+        update_note(note_id, "edited".to_string()).unwrap();
+        assert!(editable_note(note_id, &principal(3)).is_ok());
+        assert!(remove_user(note_id, principal(2)).is_err());
+
+        call_as(4);
+        add_user(note_id, principal(2), ShareRole::Editor, None).unwrap();
+        remove_user(note_id, principal(3)).unwrap();
+        call_as(2);
+        update_note(note_id, "edited again".to_string()).unwrap();
+
+        let roles: Vec<(PrincipalName, ShareRole)> = NOTES
+            .with_borrow(|notes| notes.get(&note_id))
+            .unwrap()
+            .users
+            .into_iter()
+            .map(|share| (share.user, share.role))
+            .collect();
+        assert_eq!(
+            roles,
+            [
+                (principal(2), ShareRole::Editor),
+                (principal(4), ShareRole::CoOwner)
+            ]
+        );
+        let shared = NOTE_SHARES.with_borrow(|shares| shares.get(&principal(2)).unwrap());
+        assert_eq!(shared.shares[0].role, ShareRole::Editor);
+        assert!(NOTE_SHARES.with_borrow(|shares| !shares.contains_key(&principal(3))));
+    }
+
+    #[test]
+    fn legacy_shares_grant_editor_access() {
+        let legacy = LegacyEncryptedNote {
+            id: 7,
+            encrypted_text: String::new(),
+            owner: principal(1),
+            users: vec![principal(2)],
+            chunked_content: None,
+        };
+        let note = EncryptedNote::from_bytes(Cow::Owned(Encode!(&legacy).unwrap()));
+        assert!(note.has_role(&principal(2), ShareRole::Editor));
+        assert!(!note.has_role(&principal(2), ShareRole::CoOwner));
+
+        let ids = NoteIds { ids: vec![7] };
+        let shared = SharedNotes::from_bytes(Cow::Owned(Encode!(&ids).unwrap()));
+        assert_eq!(shared.shares[0].note_id, 7);
+        assert_eq!(shared.shares[0].role, ShareRole::Editor);
+    }
+
+    #[test]
+    fn expired_shares_deny_access_and_are_pruned() {
+        system::set_time(100);
+        call_as(1);
+        let note_id = create_note().unwrap();
+        assert!(matches!(
+            add_user(note_id, principal(2), ShareRole::Viewer, Some(100)),
+            Err(Error::InvalidInput(_))
+        ));
+        add_user(note_id, principal(2), ShareRole::Viewer, Some(150)).unwrap();
+        // Extending the share replaces its scheduled expiry.
+        add_user(note_id, principal(2), ShareRole::Viewer, Some(200)).unwrap();
+        add_user(note_id, principal(3), ShareRole::Viewer, Some(300)).unwrap();
+        let expiries = || -> Vec<u64> {
+            SHARE_EXPIRIES.with_borrow(|expiries| expiries.iter().map(|(key, _)| key.0).collect())
+        };
+        assert_eq!(expiries(), [200, 300]);
+
+        call_as(2);
+        system::set_time(199);
+        assert!(get_note_chunk(note_id, 0).is_ok());
+        system::set_time(200);
+        assert!(matches!(
+            get_note_chunk(note_id, 0),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(get_notes(PageRequest::default(), false)
+            .unwrap()
+            .items
+            .is_empty());
+
+        prune_expired_shares();
+        let note = NOTES.with_borrow(|notes| notes.get(&note_id)).unwrap();
+        let users: Vec<&str> = note.users.iter().map(|share| share.user.as_str()).collect();
+        assert_eq!(users, [principal(3)]);
+        assert!(NOTE_SHARES.with_borrow(|shares| !shares.contains_key(&principal(2))));
+        assert_eq!(expiries(), [300]);
+
+        call_as(1);
+        remove_user(note_id, principal(3)).unwrap();
+        assert!(expiries().is_empty());
+    }
+
+    #[test]
+    fn migrations_run_once() {
+        call_as(1);
+        let note_id = create_note().unwrap();
+        let mark_as_legacy_synthetic = || {
+            NOTES.with_borrow_mut(|notes| {
+                let mut note = notes.get(&note_id).unwrap();
+                note.users.push(NoteShare {
+                    user: note.owner.clone(),
+                    role: ShareRole::Editor,
+                    expires_at: None,
+                });
+                notes.insert(note_id, note);
+            });
+        };
+        let shared_with = || NOTES.with_borrow(|notes| notes.get(&note_id).unwrap().users.len());
+
+        mark_as_legacy_synthetic();
+        migrate_once(1, repair_synthetic_notes);
+        assert_eq!(shared_with(), 0);
+        mark_as_legacy_synthetic();
+        migrate_once(1, repair_synthetic_notes);
+        assert_eq!(shared_with(), 1);
+    }
+
+    #[test]
+    fn pinned_versions_outlive_pruning() {
+        call_as(1);
+        let note_id = create_note().unwrap();
+        update_note(note_id, "pinned".to_string()).unwrap();
+        let pinned = latest_version_number(note_id).unwrap();
+        PINNED_VERSIONS.with_borrow_mut(|pins| pins.insert((note_id, pinned, 1), ()));
+        for i in 0..MAX_VERSIONS_PER_NOTE {
+            update_note(note_id, format!("text {i}")).unwrap();
+        }
+
+        let versions = version_numbers(note_id);
+        assert_eq!(versions.len(), MAX_VERSIONS_PER_NOTE);
+        assert_eq!(versions[0], pinned);
+        let text = |version| get_note_version(note_id, version).unwrap().encrypted_text;
+        assert_eq!(text(pinned).as_deref(), Some("pinned"));
+        let latest = *versions.last().unwrap();
+        let last_text = format!("text {}", MAX_VERSIONS_PER_NOTE - 1);
+        assert_eq!(text(latest), Some(last_text));
+        // The latest text is the note's content and not stored again.
+        assert!(VERSION_TEXTS.with_borrow(|texts| !texts.contains_key(&(note_id, latest))));
+        let listed = get_note_versions(note_id, PageRequest::default()).unwrap();
+        assert!(listed
+            .items
+            .iter()
+            .all(|version| version.encrypted_text.is_none()));
+    }
+
+    fn pending_job(job_id: SyntheticJobId, note_id: NoteId) -> SyntheticDataJob {
+        SyntheticDataJob {
+            job_id,
+            dataset_id: note_id.to_string(),
+            owner: principal(1).to_string(),
+            status: JobStatus::Pending,
+            progress: 0,
+            created_at: 1,
+            completed_at: None,
+            result_dataset_id: None,
+            error_message: None,
+            settings: SyntheticDataRequest {
+                dataset_id: note_id.to_string(),
+                num_records: 10,
+                privacy_level: "low".to_string(),
+                model_type: "statistical".to_string(),
+                preserve_correlations: false,
+                hipaa_compliant: false,
+                seed: Some(7),
+                leakage_thresholds: None,
+            },
+            seed: Some(7),
+            epsilon_spent: None,
+            leakage_report: None,
+            legacy_id: None,
+            source_version: None,
+        }
+    }
+
+    #[test]
+    fn jobs_fail_after_repeated_slice_attempts() {
+        call_as(1);
+        let note_id = create_note().unwrap();
+        update_note(note_id, "age\n41\n".to_string()).unwrap();
+        let mut job = pending_job(1, note_id);
+        job.source_version = latest_version_number(note_id);
+        add_synthetic_job(job);
+        add_synthetic_job(pending_job(2, note_id));
+        let queued = || UNFINISHED_JOBS.with_borrow(|queue| queue.iter().collect::<Vec<_>>());
+        assert_eq!(queued(), vec![(1, 0), (2, 0)]);
+
+        // A slice that runs one step resets the attempts and keeps the run.
+        dispatch_synthetic_job();
+        assert_eq!(queued(), vec![(1, 1), (2, 0)]);
+        system::start_message(SLICE_INSTRUCTIONS);
+        process_job_slice(1);
+        assert_eq!(queued(), vec![(1, 0), (2, 0)]);
+        assert!(JOB_RUNS.with_borrow(|runs| runs.contains_key(&1)));
+
+        // Slices never run from here on, as if each of them trapped.
+        for _ in 0..MAX_SLICE_ATTEMPTS {
+            dispatch_synthetic_job();
+        }
+        assert_eq!(queued(), vec![(1, MAX_SLICE_ATTEMPTS), (2, 0)]);
+        dispatch_synthetic_job();
+        let failed = SYNTHETIC_JOBS.with_borrow(|jobs| jobs.get(&1)).unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert!(failed.error_message.is_some());
+        assert_eq!(queued(), vec![(2, 0)]);
+        assert!(JOB_RUNS.with_borrow(|runs| runs.is_empty()));
+
+        cancel_synthetic_job(2).unwrap();
+        assert!(queued().is_empty());
+    }
+
+    #[test]
+    fn retries_without_source_version_are_charged() {
+        call_as(1);
+        let note_id = create_note().unwrap();
+        let spent = || {
+            PRIVACY_BUDGETS
+                .with_borrow(|budgets| budgets.get(&note_id))
+                .unwrap_or_default()
+                .spent
+        };
+        let failed_job = |source_version| {
+            let mut job = pending_job(new_job_id(), note_id);
+            job.status = JobStatus::Failed;
+            job.source_version = source_version;
+            let job_id = job.job_id;
+            add_synthetic_job(job);
+            job_id
+        };
+
+        retry_synthetic_job(failed_job(Some(1))).unwrap();
+        assert_eq!(spent(), 0.0);
+        retry_synthetic_job(failed_job(None)).unwrap();
+        assert_eq!(spent(), 8.0);
+        // The default budget of 10 cannot cover another epsilon of 8.
+        assert!(matches!(
+            retry_synthetic_job(failed_job(None)),
+            Err(Error::QuotaExceeded(_))
+        ));
+        assert_eq!(spent(), 8.0);
+    }
+
+    #[test]
+    fn jobs_run_in_bounded_steps_across_slices() {
+        call_as(1);
+        let note_id = create_note().unwrap();
+        let mut csv = String::from("id,age,city\n");
+        for i in 0..200 {
+            let city = ["Springfield", "Shelbyville"][i % 2];
+            csv.push_str(&format!("{i},{},{city}\n", 20 + i % 50));
+        }
+        update_note(note_id, csv).unwrap();
+        let run = |instructions_per_query| {
+            let mut job = pending_job(new_job_id(), note_id);
+            job.source_version = latest_version_number(note_id);
+            job.settings.num_records = 50;
+            let job_id = job.job_id;
+            add_synthetic_job(job);
+            let mut slices = 0;
+            while UNFINISHED_JOBS.with_borrow(|queue| queue.contains_key(&job_id)) {
+                system::start_message(instructions_per_query);
+                process_job_slice(job_id);
+                slices += 1;
+            }
+            let job = SYNTHETIC_JOBS
+                .with_borrow(|jobs| jobs.get(&job_id))
+                .unwrap();
+            assert_eq!(job.status, JobStatus::Completed, "{:?}", job.error_message);
+            let result: NoteId = job.result_dataset_id.unwrap().parse().unwrap();
+            let text = NOTES
+                .with_borrow(|notes| notes.get(&result))
+                .unwrap()
+                .encrypted_text;
+            (slices, text, job.leakage_report)
+        };
+
+        let (slices, text, report) = run(0);
+        assert_eq!(slices, 1);
+        // Two steps per slice.
+        let (sliced, sliced_text, sliced_report) = run(SLICE_INSTRUCTIONS / 2);
+        assert!(sliced > 60, "{} slices", sliced);
+        assert_eq!(sliced_text, text);
+        assert_eq!(sliced_report, report);
+        assert!(JOB_RUNS.with_borrow(|runs| runs.is_empty()));
+    }
+
+    #[test]
+    fn large_datasets_are_stored_in_chunks() {
+        call_as(1);
+        let text = "a,b\n".repeat(MAX_NOTE_CHARS / 4 + 1);
+        let note_id = insert_dataset(principal(1).to_string(), text.clone()).unwrap();
+        let note = NOTES.with_borrow(|notes| notes.get(&note_id)).unwrap();
+        assert!(note.encrypted_text.is_empty());
+        let content = note.chunked_content.clone().unwrap();
+        assert_eq!(content.sha256, hex::encode(Sha256::digest(text.as_bytes())));
+        assert_eq!(note_bytes(&note).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn uploads_are_hashed_as_received_and_expire() {
+        call_as(1);
+        let note_id = create_note().unwrap();
+        let upload_id = begin_note_upload(note_id, 6).unwrap();
+        append_note_chunk(upload_id, 0, b"abc".to_vec()).unwrap();
+        // Retrying the last chunk only succeeds with the same data.
+        append_note_chunk(upload_id, 0, b"abc".to_vec()).unwrap();
+        assert!(matches!(
+            append_note_chunk(upload_id, 0, b"xyz".to_vec()),
+            Err(Error::InvalidInput(_))
+        ));
+        append_note_chunk(upload_id, 1, b"def".to_vec()).unwrap();
+        let digest = hex::encode(Sha256::digest(b"abcdef"));
+        commit_note_upload(upload_id, digest).unwrap();
+        assert_eq!(get_note_chunk(note_id, 1).unwrap(), b"def");
+        assert!(UPLOAD_OWNERS.with_borrow(|owners| owners.is_empty()));
+
+        let expiring = begin_note_upload(note_id, 6).unwrap();
+        append_note_chunk(expiring, 0, b"abc".to_vec()).unwrap();
+        system::set_time(1 + UPLOAD_TTL);
+        assert!(matches!(
+            append_note_chunk(expiring, 1, b"def".to_vec()),
+            Err(Error::NotFound(_))
+        ));
+        prune_expired_uploads();
+        assert!(UPLOAD_SESSIONS.with_borrow(|sessions| sessions.is_empty()));
+        assert!(UPLOAD_OWNERS.with_borrow(|owners| owners.is_empty()));
+        assert!(CONTENT_CHUNKS.with_borrow(|chunks| !chunks.contains_key(&(expiring, 0))));
     }
-    
-    csv_lines.join("\n")
 }
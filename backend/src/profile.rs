@@ -0,0 +1,764 @@
+//! Profiling of tabular datasets: per-column types, statistics and privacy
+//! risks, plus correlations between numeric and categorical columns.
+
+use crate::csv::{RowError, Table};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// Maximum number of unreadable rows listed in a [DataProfile].
+const MAX_REPORTED_ROW_ERRORS: usize = 20;
+/// Columns with at most this many distinct values are treated as categorical.
+const MAX_CATEGORIES: usize = 20;
+const MISSING_MARKERS: [&str; 5] = ["", "null", "nan", "na", "n/a"];
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct DataProfile {
+    pub note_id: u128,
+    pub column_analysis: Vec<ColumnProfile>,
+    pub privacy_risks: Vec<PrivacyRisk>,
+    pub correlations: Vec<ColumnCorrelation>,
+    pub recommendations: Vec<String>,
+    pub total_rows: u32,
+    pub total_columns: u32,
+    /// Number of records that could not be read and were left out.
+    pub skipped_rows: u32,
+    /// The first [MAX_REPORTED_ROW_ERRORS] unreadable records.
+    pub row_errors: Vec<RowError>,
+    pub created_at: u64,
+}
+
+impl Storable for DataProfile {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct ColumnProfile {
+    pub name: String,
+    pub data_type: String, // "numeric", "categorical", "text", "email", "phone", "id", "date"
+    pub unique_count: u32,
+    pub missing_count: u32,
+    pub total_count: u32,
+    pub examples: Vec<String>,
+    pub stats: Option<NumericStats>,
+    pub top_values: Option<Vec<(String, u32)>>,
+    pub privacy_level: String, // "low", "medium", "high"
+    pub pii_detected: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct NumericStats {
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+    pub median: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct PrivacyRisk {
+    pub risk_type: String, // "pii", "high_uniqueness", "small_groups"
+    pub column: String,
+    pub severity: String, // "low", "medium", "high"
+    pub description: String,
+    pub recommendation: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct ColumnCorrelation {
+    pub col1: String,
+    pub col2: String,
+    /// Pearson correlation; categorical columns are ordinal-encoded.
+    pub correlation: f64,
+    /// Spearman rank correlation.
+    pub spearman: f64,
+    pub correlation_type: String, // "strong", "moderate", "weak"
+}
+
+/// Profiles `table`, the content of note `note_id`, at time `now`.
+pub fn analyze_table(table: &Table, note_id: u128, now: u64) -> DataProfile {
+    let mut column_profiles = Vec::new();
+    let mut privacy_risks = Vec::new();
+
+    for (col_idx, header) in table.headers.iter().enumerate() {
+        let column_data: Vec<&str> = table.rows.iter().map(|row| row[col_idx].trim()).collect();
+        let profile = analyze_column(header, &column_data);
+
+        // Check for privacy risks
+        if profile.pii_detected {
+            privacy_risks.push(PrivacyRisk {
+                risk_type: "pii".to_string(),
+                column: header.clone(),
+                severity: "high".to_string(),
+                description: format!(
+                    "Column '{header}' may contain personally identifiable information"
+                ),
+                recommendation: "Consider anonymizing or removing this column".to_string(),
+            });
+        }
+
+        let present = profile.total_count - profile.missing_count;
+        if present > 0 && profile.unique_count as f64 / present as f64 > 0.9 {
+            privacy_risks.push(PrivacyRisk {
+                risk_type: "high_uniqueness".to_string(),
+                column: header.clone(),
+                severity: "medium".to_string(),
+                description: format!(
+                    "Column '{header}' has high uniqueness (potential identifier)"
+                ),
+                recommendation: "Consider generalizing values or adding noise".to_string(),
+            });
+        }
+
+        column_profiles.push(profile);
+    }
+
+    let correlations = calculate_correlations(table, &column_profiles);
+    let recommendations = generate_recommendations(&column_profiles, &privacy_risks);
+
+    DataProfile {
+        note_id,
+        column_analysis: column_profiles,
+        privacy_risks,
+        correlations,
+        recommendations,
+        total_rows: table.rows.len() as u32,
+        total_columns: table.headers.len() as u32,
+        skipped_rows: table.errors.len() as u32,
+        row_errors: table
+            .errors
+            .iter()
+            .take(MAX_REPORTED_ROW_ERRORS)
+            .cloned()
+            .collect(),
+        created_at: now,
+    }
+}
+
+/// Returns true if `value` denotes a missing value.
+pub fn is_missing(value: &str) -> bool {
+    MISSING_MARKERS
+        .iter()
+        .any(|marker| value.eq_ignore_ascii_case(marker))
+}
+
+fn analyze_column(header: &str, data: &[&str]) -> ColumnProfile {
+    let total_count = data.len() as u32;
+    let non_empty_data: Vec<&str> = data.iter().copied().filter(|v| !is_missing(v)).collect();
+    let missing_count = total_count - non_empty_data.len() as u32;
+    let unique_count = non_empty_data.iter().collect::<HashSet<_>>().len() as u32;
+
+    // Data type detection
+    let data_type = detect_data_type(header, &non_empty_data);
+    let pii_detected = detect_pii(header, &non_empty_data);
+
+    // Privacy level assessment
+    let privacy_level = if pii_detected {
+        "high"
+    } else if !non_empty_data.is_empty() && unique_count as f64 / non_empty_data.len() as f64 > 0.8
+    {
+        "medium"
+    } else {
+        "low"
+    };
+
+    // Get examples (first 3 non-empty values)
+    let examples = non_empty_data
+        .iter()
+        .take(3)
+        .map(|s| s.to_string())
+        .collect();
+
+    // Calculate statistics for numeric data
+    let stats = if data_type == "numeric" {
+        calculate_numeric_stats(&non_empty_data)
+    } else {
+        None
+    };
+
+    // Get top values for categorical data
+    let top_values = if data_type == "categorical" {
+        let mut sorted_values: Vec<(String, u32)> = value_counts(&non_empty_data)
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        sorted_values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Some(sorted_values.into_iter().take(5).collect())
+    } else {
+        None
+    };
+
+    ColumnProfile {
+        name: header.to_string(),
+        data_type: data_type.to_string(),
+        unique_count,
+        missing_count,
+        total_count,
+        examples,
+        stats,
+        top_values,
+        privacy_level: privacy_level.to_string(),
+        pii_detected,
+    }
+}
+
+fn value_counts<'a>(data: &[&'a str]) -> HashMap<&'a str, u32> {
+    let mut counts = HashMap::new();
+    for &value in data {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Fraction of `data` for which `predicate` holds; zero for empty columns.
+fn share(data: &[&str], predicate: impl Fn(&str) -> bool) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    data.iter().filter(|v| predicate(v)).count() as f64 / data.len() as f64
+}
+
+/// Classifies a column as one of the [ColumnProfile::data_type] values.
+pub fn detect_data_type(header: &str, data: &[&str]) -> &'static str {
+    // Check for ID patterns in the words of the column name
+    let is_id_name = header
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| matches!(word, "id" | "uuid" | "key"))
+        || header.ends_with("Id")
+        || header.ends_with("ID");
+    if is_id_name {
+        return "id";
+    }
+
+    if share(data, is_email) > 0.5 {
+        return "email";
+    }
+    if share(data, |v| v.parse::<f64>().is_ok()) > 0.8 {
+        return "numeric";
+    }
+    if share(data, is_date) > 0.8 {
+        return "date";
+    }
+    if share(data, is_phone) > 0.5 {
+        return "phone";
+    }
+
+    // Check unique values for categorical vs text
+    let unique = data.iter().collect::<HashSet<_>>().len();
+    if unique <= MAX_CATEGORIES || (unique as f64) < data.len() as f64 * 0.1 {
+        "categorical"
+    } else {
+        "text"
+    }
+}
+
+/// Returns true if the column name or its first values suggest personal data.
+pub fn detect_pii(header: &str, data: &[&str]) -> bool {
+    let header_lower = header.to_lowercase();
+
+    // PII indicators in column names
+    let pii_keywords = [
+        "name", "email", "phone", "address", "ssn", "social", "credit", "card", "license",
+    ];
+    if pii_keywords
+        .iter()
+        .any(|keyword| header_lower.contains(keyword))
+    {
+        return true;
+    }
+
+    // Pattern detection in the first values
+    data.iter()
+        .take(10)
+        .any(|value| is_email(value) || is_phone(value) || is_ssn(value))
+}
+
+fn is_email(value: &str) -> bool {
+    match value.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && domain.contains('.') && !domain.starts_with('.')
+        }
+        None => false,
+    }
+}
+
+/// Phone numbers have 10 to 15 digits and at least one formatting character,
+/// which tells them apart from plain numbers.
+fn is_phone(value: &str) -> bool {
+    let digits = value.chars().filter(|c| c.is_ascii_digit()).count();
+    (10..=15).contains(&digits)
+        && value
+            .chars()
+            .all(|c| c.is_ascii_digit() || " +-().".contains(c))
+        && value.chars().any(|c| " +-()".contains(c))
+}
+
+/// US social security numbers (`123-45-6789`).
+fn is_ssn(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    parts.len() == 3
+        && parts
+            .iter()
+            .zip([3, 2, 4])
+            .all(|(part, len)| part.len() == len && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Dates like `2024-03-01`, `01/03/2024` or `2024/3/1`, optionally followed by a time.
+fn is_date(value: &str) -> bool {
+    let date = value.split([' ', 'T']).next().unwrap_or_default();
+    let parts: Vec<&str> = date.split(['-', '/', '.']).collect();
+    parts.len() == 3
+        && parts.iter().all(|part| {
+            !part.is_empty() && part.len() <= 4 && part.chars().all(|c| c.is_ascii_digit())
+        })
+        && (parts[0].len() == 4 || parts[2].len() == 4)
+}
+
+fn calculate_numeric_stats(data: &[&str]) -> Option<NumericStats> {
+    let numbers: Vec<f64> = data
+        .iter()
+        .filter_map(|s| s.parse::<f64>().ok())
+        .filter(|x| x.is_finite())
+        .collect();
+
+    if numbers.is_empty() {
+        return None;
+    }
+
+    let sum: f64 = numbers.iter().sum();
+    let mean = sum / numbers.len() as f64;
+
+    let variance: f64 =
+        numbers.iter().map(|&x| (x - mean).powi(2)).sum::<f64>() / numbers.len() as f64;
+    let std = variance.sqrt();
+
+    let mut sorted_numbers = numbers;
+    sorted_numbers.sort_by(f64::total_cmp);
+
+    let n = sorted_numbers.len();
+    let median = if n.is_multiple_of(2) {
+        (sorted_numbers[n / 2 - 1] + sorted_numbers[n / 2]) / 2.0
+    } else {
+        sorted_numbers[n / 2]
+    };
+
+    Some(NumericStats {
+        mean,
+        std,
+        min: sorted_numbers[0],
+        max: sorted_numbers[n - 1],
+        median,
+    })
+}
+
+/// Pairwise correlations of the numeric and categorical columns of `table`.
+fn calculate_correlations(table: &Table, columns: &[ColumnProfile]) -> Vec<ColumnCorrelation> {
+    let encoded: Vec<(usize, Vec<Option<f64>>)> = columns
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, column)| {
+            let values: Vec<&str> = table.rows.iter().map(|row| row[idx].trim()).collect();
+            Some((idx, encode_column(&values, &column.data_type)?))
+        })
+        .collect();
+    let matrix = CorrelationMatrix::compute(
+        &encoded
+            .iter()
+            .map(|(_, values)| values.clone())
+            .collect::<Vec<_>>(),
+    );
+
+    let mut correlations = Vec::new();
+    for (i, (a, _)) in encoded.iter().enumerate() {
+        for (j, (b, _)) in encoded.iter().enumerate().skip(i + 1) {
+            let (pearson, spearman) = (matrix.pearson[i][j], matrix.spearman[i][j]);
+            correlations.push(ColumnCorrelation {
+                col1: columns[*a].name.clone(),
+                col2: columns[*b].name.clone(),
+                correlation: pearson,
+                spearman,
+                correlation_type: correlation_strength(pearson.abs().max(spearman.abs()))
+                    .to_string(),
+            });
+        }
+    }
+    correlations
+}
+
+/// Encodes a numeric or categorical column as numbers for correlation
+/// analysis. Categories are replaced by their position in [ordinal_sort_by_key]
+/// order. Missing values become `None`; other column types are not encoded.
+pub fn encode_column(values: &[&str], data_type: &str) -> Option<Vec<Option<f64>>> {
+    match data_type {
+        "numeric" => Some(
+            values
+                .iter()
+                .map(|v| v.parse::<f64>().ok().filter(|x| x.is_finite()))
+                .collect(),
+        ),
+        "categorical" => {
+            let mut categories: Vec<&str> =
+                values.iter().copied().filter(|v| !is_missing(v)).collect();
+            categories.sort_unstable();
+            categories.dedup();
+            ordinal_sort_by_key(&mut categories, |c| *c);
+            let codes: HashMap<&str, f64> = categories
+                .into_iter()
+                .enumerate()
+                .map(|(code, category)| (category, code as f64))
+                .collect();
+            Some(values.iter().map(|v| codes.get(v).copied()).collect())
+        }
+        _ => None,
+    }
+}
+
+/// Orders `items` by `key`: numerically if every key is a number and
+/// lexicographically otherwise.
+pub fn ordinal_sort_by_key<T>(items: &mut [T], key: impl Fn(&T) -> &str) {
+    let numeric = items
+        .iter()
+        .all(|item| key(item).parse::<f64>().is_ok_and(f64::is_finite));
+    if numeric {
+        items.sort_by(|a, b| {
+            let a: f64 = key(a).parse().unwrap();
+            let b: f64 = key(b).parse().unwrap();
+            a.total_cmp(&b)
+        });
+    } else {
+        items.sort_by(|a, b| key(a).cmp(key(b)));
+    }
+}
+
+/// Pearson and Spearman correlation matrices of encoded columns. Every pair
+/// is computed over the rows in which both values are present; pairs without
+/// variation get a correlation of zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorrelationMatrix {
+    pub pearson: Vec<Vec<f64>>,
+    pub spearman: Vec<Vec<f64>>,
+}
+
+impl CorrelationMatrix {
+    pub fn compute(columns: &[Vec<Option<f64>>]) -> Self {
+        let n = columns.len();
+        let mut pearson_matrix = identity(n);
+        let mut spearman_matrix = identity(n);
+        let full_ranks: Vec<Option<Vec<f64>>> = columns.iter().map(|c| full_ranks(c)).collect();
+        for a in 0..n {
+            for b in a + 1..n {
+                let (pearson_ab, spearman_ab) = correlate(columns, &full_ranks, a, b);
+                pearson_matrix[a][b] = pearson_ab;
+                pearson_matrix[b][a] = pearson_ab;
+                spearman_matrix[a][b] = spearman_ab;
+                spearman_matrix[b][a] = spearman_ab;
+            }
+        }
+        Self {
+            pearson: pearson_matrix,
+            spearman: spearman_matrix,
+        }
+    }
+}
+
+/// The Spearman matrix of a [CorrelationMatrix], computed one column ranking
+/// or one pair of columns per [SpearmanMatrix::step].
+pub struct SpearmanMatrix {
+    columns: Vec<Vec<Option<f64>>>,
+    full_ranks: Vec<Option<Vec<f64>>>,
+    matrix: Vec<Vec<f64>>,
+    /// Next pair of columns to correlate once all columns are ranked.
+    next: (usize, usize),
+}
+
+impl SpearmanMatrix {
+    pub fn new(columns: Vec<Vec<Option<f64>>>) -> Self {
+        Self {
+            matrix: identity(columns.len()),
+            columns,
+            full_ranks: Vec::new(),
+            next: (0, 1),
+        }
+    }
+
+    /// Ranks the next column or correlates the next pair of columns. Returns
+    /// true once the matrix is complete.
+    pub fn step(&mut self) -> bool {
+        let n = self.columns.len();
+        if self.full_ranks.len() < n {
+            let ranks = full_ranks(&self.columns[self.full_ranks.len()]);
+            self.full_ranks.push(ranks);
+            return false;
+        }
+        let (a, b) = self.next;
+        if b >= n {
+            return true;
+        }
+        let (_, spearman) = correlate(&self.columns, &self.full_ranks, a, b);
+        self.matrix[a][b] = spearman;
+        self.matrix[b][a] = spearman;
+        self.next = if b + 1 < n {
+            (a, b + 1)
+        } else {
+            (a + 1, a + 2)
+        };
+        self.next.1 >= n
+    }
+
+    pub fn finish(self) -> Vec<Vec<f64>> {
+        self.matrix
+    }
+}
+
+/// Ranks of `column`, computed once for columns without missing values.
+fn full_ranks(column: &[Option<f64>]) -> Option<Vec<f64>> {
+    let values: Option<Vec<f64>> = column.iter().copied().collect();
+    values.map(|values| ranks(&values))
+}
+
+/// Pearson and Spearman correlations of columns `a` and `b`.
+fn correlate(
+    columns: &[Vec<Option<f64>>],
+    full_ranks: &[Option<Vec<f64>>],
+    a: usize,
+    b: usize,
+) -> (f64, f64) {
+    let (pearson_ab, spearman_ab) = match (&full_ranks[a], &full_ranks[b]) {
+        (Some(ranks_a), Some(ranks_b)) => {
+            let pairs: Vec<(f64, f64)> = columns[a]
+                .iter()
+                .zip(&columns[b])
+                .map(|(x, y)| (x.unwrap_or_default(), y.unwrap_or_default()))
+                .collect();
+            let rank_pairs: Vec<(f64, f64)> = ranks_a
+                .iter()
+                .copied()
+                .zip(ranks_b.iter().copied())
+                .collect();
+            (pearson(&pairs), pearson(&rank_pairs))
+        }
+        _ => {
+            let pairs: Vec<(f64, f64)> = columns[a]
+                .iter()
+                .zip(&columns[b])
+                .filter_map(|(x, y)| Some(((*x)?, (*y)?)))
+                .collect();
+            let (xs, ys): (Vec<f64>, Vec<f64>) = pairs.iter().copied().unzip();
+            let rank_pairs: Vec<(f64, f64)> = ranks(&xs).into_iter().zip(ranks(&ys)).collect();
+            (pearson(&pairs), pearson(&rank_pairs))
+        }
+    };
+    (pearson_ab.unwrap_or(0.0), spearman_ab.unwrap_or(0.0))
+}
+
+fn identity(n: usize) -> Vec<Vec<f64>> {
+    (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect()
+}
+
+/// 1-based ranks of `values`, averaging the ranks of ties.
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+    let mut result = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        for &idx in &order[start..end] {
+            result[idx] = rank;
+        }
+        start = end;
+    }
+    result
+}
+
+/// Pearson correlation coefficient, or `None` if either side is constant.
+pub fn pearson(pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for &(x, y) in pairs {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+    Some((cov / (var_x * var_y).sqrt()).clamp(-1.0, 1.0))
+}
+
+fn correlation_strength(correlation: f64) -> &'static str {
+    match correlation.abs() {
+        r if r >= 0.7 => "strong",
+        r if r >= 0.4 => "moderate",
+        _ => "weak",
+    }
+}
+
+fn generate_recommendations(columns: &[ColumnProfile], risks: &[PrivacyRisk]) -> Vec<String> {
+    let mut recommendations = Vec::new();
+    for column in columns {
+        if column.total_count > 0 && column.missing_count * 5 > column.total_count {
+            recommendations.push(format!(
+                "Column '{}' is missing {} of {} values; consider imputing or dropping it",
+                column.name, column.missing_count, column.total_count
+            ));
+        }
+    }
+    for risk in risks {
+        recommendations.push(format!("{}: {}", risk.column, risk.recommendation));
+    }
+    if columns.iter().any(|c| c.pii_detected) {
+        recommendations.push(
+            "Generate synthetic data with HIPAA compliance enabled before sharing".to_string(),
+        );
+    }
+    recommendations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATIENTS: &str = "\
+patient_id,age,sex,diagnosis,weight,height,email
+1,34,F,flu,61.5,165,ann@example.com
+2,45,M,flu,80.2,180,bob@example.com
+3,29,F,cold,55.0,160,cid@example.com
+4,61,M,diabetes,92.3,178,dan@example.com
+5,52,F,NULL,70.1,170,eve@example.com
+6,38,M,cold,77.7,175,fay@example.com
+";
+
+    fn profile(csv: &str) -> DataProfile {
+        analyze_table(&Table::read(csv.as_bytes()), 7, 0)
+    }
+
+    fn column<'a>(profile: &'a DataProfile, name: &str) -> &'a ColumnProfile {
+        profile
+            .column_analysis
+            .iter()
+            .find(|c| c.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn detects_column_types() {
+        let profile = profile(PATIENTS);
+        assert_eq!(profile.total_rows, 6);
+        assert_eq!(profile.total_columns, 7);
+        let types: Vec<&str> = profile
+            .column_analysis
+            .iter()
+            .map(|c| c.data_type.as_str())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "id",
+                "numeric",
+                "categorical",
+                "categorical",
+                "numeric",
+                "numeric",
+                "email"
+            ]
+        );
+    }
+
+    #[test]
+    fn computes_numeric_stats_and_missing_values() {
+        let profile = profile(PATIENTS);
+        let stats = column(&profile, "age").stats.clone().unwrap();
+        assert_eq!(stats.min, 29.0);
+        assert_eq!(stats.max, 61.0);
+        assert_eq!(stats.median, 41.5);
+        assert!((stats.mean - 43.1667).abs() < 1e-3);
+
+        let diagnosis = column(&profile, "diagnosis");
+        assert_eq!(diagnosis.missing_count, 1);
+        assert_eq!(diagnosis.unique_count, 3);
+        assert_eq!(
+            diagnosis.top_values.as_ref().unwrap()[0],
+            ("cold".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn flags_pii_columns() {
+        let profile = profile(PATIENTS);
+        assert!(column(&profile, "email").pii_detected);
+        assert!(!column(&profile, "age").pii_detected);
+        assert!(profile
+            .privacy_risks
+            .iter()
+            .any(|r| r.risk_type == "pii" && r.column == "email"));
+    }
+
+    #[test]
+    fn correlates_numeric_columns() {
+        let profile = profile(PATIENTS);
+        let weight_height = profile
+            .correlations
+            .iter()
+            .find(|c| c.col1 == "weight" && c.col2 == "height")
+            .unwrap();
+        assert!(weight_height.correlation > 0.9);
+        assert_eq!(weight_height.correlation_type, "strong");
+    }
+
+    #[test]
+    fn computes_rank_correlations_including_categorical_columns() {
+        let columns = vec![
+            vec![Some(1.0), Some(2.0), Some(3.0), Some(4.0), Some(5.0)],
+            vec![Some(1.0), Some(4.0), Some(9.0), Some(16.0), Some(1000.0)],
+            vec![Some(5.0), None, Some(3.0), Some(2.0), Some(1.0)],
+        ];
+        let matrix = CorrelationMatrix::compute(&columns);
+        assert!(matrix.pearson[0][1] < 0.9);
+        assert!((matrix.spearman[0][1] - 1.0).abs() < 1e-12);
+        assert!((matrix.spearman[2][0] + 1.0).abs() < 1e-12);
+        assert_eq!(matrix.pearson[1][1], 1.0);
+        let mut steps = SpearmanMatrix::new(columns);
+        while !steps.step() {}
+        assert_eq!(steps.finish(), matrix.spearman);
+
+        let encoded = encode_column(&["10", "9", "", "100"], "categorical").unwrap();
+        assert_eq!(encoded, [Some(1.0), Some(0.0), None, Some(2.0)]);
+
+        let profile = profile(PATIENTS);
+        assert!(profile
+            .correlations
+            .iter()
+            .any(|c| c.col1 == "sex" && c.col2 == "diagnosis"));
+    }
+
+    #[test]
+    fn handles_quoted_fields_and_reports_bad_rows() {
+        let csv = "name;notes;score\n\"Doe; Jane\";\"said \"\"hi\"\"\nthen left\";3\nbroken;row\n\"Roe, Ray\";ok;4\n";
+        let profile = profile(csv);
+        assert_eq!(profile.total_rows, 2);
+        assert_eq!(profile.skipped_rows, 1);
+        assert_eq!(profile.row_errors[0].line, 4);
+        assert_eq!(column(&profile, "name").examples[0], "Doe; Jane");
+        assert!(column(&profile, "name").pii_detected);
+    }
+}
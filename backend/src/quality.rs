@@ -0,0 +1,318 @@
+//! Fidelity of synthetic datasets to their source.
+//!
+//! Numeric columns are compared by the two-sample Kolmogorov-Smirnov
+//! statistic and categorical ones by the total variation distance of their
+//! value frequencies, both in `[0, 1]` with 0 for identical distributions.
+//! Dependence between columns is compared by the mean absolute difference of
+//! the Spearman correlation matrices. Identifier and masked columns are not
+//! compared, as their values are not meant to resemble the source.
+
+use crate::csv::Table;
+use crate::profile::{detect_data_type, is_missing, ordinal_sort_by_key, SpearmanMatrix};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct QualityReport {
+    pub columns: Vec<ColumnQuality>,
+    /// Mean absolute difference between the off-diagonal Spearman
+    /// correlations of source and output, if at least two columns are compared.
+    pub correlation_difference: Option<f64>,
+    /// 0-100 score: the mean of the per-column scores and, if available, one
+    /// minus the correlation difference.
+    pub composite_score: u8,
+    pub created_at: u64,
+}
+
+impl Storable for QualityReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct ColumnQuality {
+    pub column: String,
+    pub data_type: String,
+    /// Kolmogorov-Smirnov statistic of a numeric column.
+    pub ks_statistic: Option<f64>,
+    /// Total variation distance of a categorical column, including missing values.
+    pub total_variation: Option<f64>,
+    /// Share of source categories that occur in the output.
+    pub category_coverage: Option<f64>,
+    /// One minus the KS statistic or the total variation distance.
+    pub score: f64,
+}
+
+/// Compares `synthetic` with `source` at time `now`.
+#[cfg(test)]
+pub fn evaluate(source: &Table, synthetic: &Table, now: u64) -> QualityReport {
+    let mut evaluation = Evaluation::default();
+    while !evaluation.step(source, synthetic) {}
+    evaluation.finish(now)
+}
+
+/// An [evaluate] done one column, or one pair of columns of the correlation
+/// matrices, per [Evaluation::step].
+#[derive(Default)]
+pub struct Evaluation {
+    columns: Vec<ColumnQuality>,
+    encoded_source: Vec<Vec<Option<f64>>>,
+    encoded_synthetic: Vec<Vec<Option<f64>>>,
+    /// Next source column to compare.
+    next_column: usize,
+    /// Spearman matrices of the source and the output, once all columns are
+    /// compared, if at least two of them are encoded.
+    correlations: Option<(SpearmanMatrix, SpearmanMatrix)>,
+}
+
+impl Evaluation {
+    /// Does the next part of the comparison of `synthetic` with `source`, the
+    /// same tables in every call. Returns true once it is complete.
+    pub fn step(&mut self, source: &Table, synthetic: &Table) -> bool {
+        if self.next_column < source.headers.len() {
+            self.compare_column(source, synthetic, self.next_column);
+            self.next_column += 1;
+            return false;
+        }
+        if self.correlations.is_none() && self.encoded_source.len() >= 2 {
+            self.correlations = Some((
+                SpearmanMatrix::new(std::mem::take(&mut self.encoded_source)),
+                SpearmanMatrix::new(std::mem::take(&mut self.encoded_synthetic)),
+            ));
+        }
+        match &mut self.correlations {
+            Some((expected, actual)) => expected.step() && actual.step(),
+            None => true,
+        }
+    }
+
+    fn compare_column(&mut self, source: &Table, synthetic: &Table, idx: usize) {
+        let header = &source.headers[idx];
+        let Some(synthetic_idx) = synthetic.headers.iter().position(|h| h == header) else {
+            return;
+        };
+        let source_values = column_values(source, idx);
+        let synthetic_values = column_values(synthetic, synthetic_idx);
+        let present: Vec<&str> = source_values
+            .iter()
+            .copied()
+            .filter(|v| !is_missing(v))
+            .collect();
+        let data_type = detect_data_type(header, &present);
+        let quality = match data_type {
+            "numeric" => {
+                let ks = ks_statistic(&numbers(&source_values), &numbers(&synthetic_values));
+                self.encoded_source
+                    .push(source_values.iter().map(|v| number(v)).collect());
+                self.encoded_synthetic
+                    .push(synthetic_values.iter().map(|v| number(v)).collect());
+                ColumnQuality {
+                    column: header.clone(),
+                    data_type: data_type.to_string(),
+                    ks_statistic: Some(ks),
+                    total_variation: None,
+                    category_coverage: None,
+                    score: 1.0 - ks,
+                }
+            }
+            "categorical" | "date" => {
+                let source_counts = frequencies(&source_values);
+                let synthetic_counts = frequencies(&synthetic_values);
+                let tvd = total_variation(&source_counts, &synthetic_counts);
+                let categories: Vec<&str> = present
+                    .iter()
+                    .copied()
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+                let covered = categories
+                    .iter()
+                    .filter(|c| synthetic_counts.contains_key(*c))
+                    .count();
+                if data_type == "categorical" {
+                    let codes = ordinal_codes(categories.clone());
+                    let encode = |values: &[&str]| -> Vec<Option<f64>> {
+                        values.iter().map(|v| codes.get(v).copied()).collect()
+                    };
+                    self.encoded_source.push(encode(&source_values));
+                    self.encoded_synthetic.push(encode(&synthetic_values));
+                }
+                ColumnQuality {
+                    column: header.clone(),
+                    data_type: data_type.to_string(),
+                    ks_statistic: None,
+                    total_variation: Some(tvd),
+                    category_coverage: Some(if categories.is_empty() {
+                        1.0
+                    } else {
+                        covered as f64 / categories.len() as f64
+                    }),
+                    score: 1.0 - tvd,
+                }
+            }
+            // Identifiers and masked values are not expected to match.
+            _ => return,
+        };
+        self.columns.push(quality);
+    }
+
+    pub fn finish(self, now: u64) -> QualityReport {
+        let columns = self.columns;
+        let correlation_difference = self.correlations.map(|(expected, actual)| {
+            let (expected, actual) = (expected.finish(), actual.finish());
+            let n = expected.len();
+            let pairs = (n * (n - 1) / 2) as f64;
+            let total: f64 = (0..n)
+                .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+                .map(|(i, j)| (expected[i][j] - actual[i][j]).abs())
+                .sum();
+            total / pairs
+        });
+
+        let mut scores: Vec<f64> = Vec::new();
+        if !columns.is_empty() {
+            scores.push(columns.iter().map(|c| c.score).sum::<f64>() / columns.len() as f64);
+        }
+        if let Some(difference) = correlation_difference {
+            scores.push((1.0 - difference).max(0.0));
+        }
+        let composite = if scores.is_empty() {
+            0.0
+        } else {
+            scores.iter().sum::<f64>() / scores.len() as f64
+        };
+
+        QualityReport {
+            columns,
+            correlation_difference,
+            composite_score: (composite * 100.0).clamp(0.0, 100.0).round() as u8,
+            created_at: now,
+        }
+    }
+}
+
+fn column_values(table: &Table, idx: usize) -> Vec<&str> {
+    table.rows.iter().map(|row| row[idx].trim()).collect()
+}
+
+fn number(value: &str) -> Option<f64> {
+    value.parse::<f64>().ok().filter(|x| x.is_finite())
+}
+
+fn numbers(values: &[&str]) -> Vec<f64> {
+    let mut numbers: Vec<f64> = values.iter().filter_map(|v| number(v)).collect();
+    numbers.sort_by(f64::total_cmp);
+    numbers
+}
+
+/// Two-sample Kolmogorov-Smirnov statistic of ascending samples: the largest
+/// difference between their empirical CDFs. 1 if exactly one is empty.
+fn ks_statistic(a: &[f64], b: &[f64]) -> f64 {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => return 0.0,
+        (true, false) | (false, true) => return 1.0,
+        _ => {}
+    }
+    let (mut i, mut j, mut max) = (0, 0, 0.0f64);
+    while i < a.len() && j < b.len() {
+        let x = a[i].min(b[j]);
+        while i < a.len() && a[i] <= x {
+            i += 1;
+        }
+        while j < b.len() && b[j] <= x {
+            j += 1;
+        }
+        let difference = (i as f64 / a.len() as f64 - j as f64 / b.len() as f64).abs();
+        max = max.max(difference);
+    }
+    max
+}
+
+/// Relative frequencies of values; missing values are counted as "".
+fn frequencies<'a>(values: &[&'a str]) -> HashMap<&'a str, f64> {
+    let mut counts: HashMap<&str, f64> = HashMap::new();
+    for value in values {
+        let key = if is_missing(value) { "" } else { value };
+        *counts.entry(key).or_default() += 1.0;
+    }
+    let total = values.len().max(1) as f64;
+    counts.values_mut().for_each(|count| *count /= total);
+    counts
+}
+
+fn total_variation(a: &HashMap<&str, f64>, b: &HashMap<&str, f64>) -> f64 {
+    let keys: HashSet<&str> = a.keys().chain(b.keys()).copied().collect();
+    let sum: f64 = keys
+        .into_iter()
+        .map(|key| (a.get(key).unwrap_or(&0.0) - b.get(key).unwrap_or(&0.0)).abs())
+        .sum();
+    (sum / 2.0).min(1.0)
+}
+
+/// Codes of categories in ordinal order, as used for correlations.
+fn ordinal_codes(mut categories: Vec<&str>) -> HashMap<&str, f64> {
+    ordinal_sort_by_key(&mut categories, |c| *c);
+    categories
+        .into_iter()
+        .enumerate()
+        .map(|(code, category)| (category, code as f64))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    fn dataset(shift: f64, seed: u64) -> Table {
+        let mut csv = String::from("id,age,income,plan\n");
+        let mut rng = Rng::seed_from_u64(seed);
+        for i in 0..500 {
+            let age = 40.0 + shift + 10.0 * rng.normal();
+            let income = 1_000.0 * age + 5_000.0 * rng.normal();
+            let plan = if rng.chance(0.3) { "basic" } else { "premium" };
+            csv.push_str(&format!("{},{:.0},{:.0},{}\n", i, age, income, plan));
+        }
+        Table::read(csv.as_bytes())
+    }
+
+    #[test]
+    fn identical_datasets_score_full_marks() {
+        let source = dataset(0.0, 1);
+        let report = evaluate(&source, &source, 3);
+        assert_eq!(report.composite_score, 100);
+        assert_eq!(report.correlation_difference, Some(0.0));
+        assert_eq!(report.created_at, 3);
+        let names: Vec<&str> = report.columns.iter().map(|c| c.column.as_str()).collect();
+        assert_eq!(names, ["age", "income", "plan"]);
+        assert_eq!(report.columns[2].category_coverage, Some(1.0));
+    }
+
+    #[test]
+    fn differences_lower_the_score() {
+        let source = dataset(0.0, 1);
+        let similar = evaluate(&source, &dataset(0.0, 2), 0);
+        let shifted = evaluate(&source, &dataset(15.0, 2), 0);
+        assert!(similar.composite_score >= 90, "{}", similar.composite_score);
+        assert!(shifted.composite_score < similar.composite_score);
+        let ks = shifted.columns[0].ks_statistic.unwrap();
+        assert!(ks > 0.4, "ks {}", ks);
+    }
+
+    #[test]
+    fn computes_ks_statistic_and_total_variation() {
+        assert_eq!(ks_statistic(&[1.0, 2.0, 3.0], &[1.0, 2.0, 3.0]), 0.0);
+        assert_eq!(ks_statistic(&[1.0, 2.0], &[3.0, 4.0]), 1.0);
+        assert_eq!(ks_statistic(&[1.0, 2.0, 3.0, 4.0], &[3.0, 4.0]), 0.5);
+        let a = frequencies(&["x", "x", "y", ""]);
+        let b = frequencies(&["x", "y", "y", "y"]);
+        assert!((total_variation(&a, &b) - 0.5).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,97 @@
+//! SHA-256 computed across calls. The state is stored with an upload between
+//! its chunks, so that committing the upload does not read all of them again.
+
+use candid::{CandidType, Deserialize};
+use sha2::digest::generic_array::GenericArray;
+use std::convert::TryInto;
+
+const BLOCK_BYTES: usize = 64;
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct Sha256State {
+    state: Vec<u32>,
+    /// Input not yet hashed, shorter than a block.
+    pending: Vec<u8>,
+    /// Number of bytes hashed or pending.
+    length: u64,
+}
+
+impl Default for Sha256State {
+    fn default() -> Self {
+        Self {
+            state: INITIAL_STATE.to_vec(),
+            pending: Vec::new(),
+            length: 0,
+        }
+    }
+}
+
+impl Sha256State {
+    pub fn update(&mut self, data: &[u8]) {
+        self.length += data.len() as u64;
+        self.pending.extend_from_slice(data);
+        let full = self.pending.len() - self.pending.len() % BLOCK_BYTES;
+        let blocks: Vec<u8> = self.pending.drain(..full).collect();
+        self.compress(&blocks);
+    }
+
+    /// Hex-encoded hash of all the data passed to [Sha256State::update].
+    pub fn finalize(mut self) -> String {
+        let bit_length = self.length.wrapping_mul(8);
+        let mut tail = std::mem::take(&mut self.pending);
+        tail.push(0x80);
+        while tail.len() % BLOCK_BYTES != BLOCK_BYTES - 8 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&bit_length.to_be_bytes());
+        self.compress(&tail);
+        hex::encode(
+            self.state
+                .iter()
+                .flat_map(|word| word.to_be_bytes())
+                .collect::<Vec<u8>>(),
+        )
+    }
+
+    /// Hashes `blocks`, whose length is a multiple of the block size.
+    fn compress(&mut self, blocks: &[u8]) {
+        let blocks: Vec<_> = blocks
+            .as_chunks::<BLOCK_BYTES>()
+            .0
+            .iter()
+            .map(|block| GenericArray::from(*block))
+            .collect();
+        let mut state: [u32; 8] = self.state[..]
+            .try_into()
+            .expect("SHA-256 state has 8 words");
+        sha2::compress256(&mut state, &blocks);
+        self.state = state.to_vec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn matches_sha256_of_the_concatenated_input() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for split in [0, 1, 55, 56, 63, 64, 65, 500, 1000] {
+            for end in [split, 1000] {
+                let mut state = Sha256State::default();
+                state.update(&data[..split]);
+                state.update(&data[split..end]);
+                assert_eq!(
+                    state.finalize(),
+                    hex::encode(Sha256::digest(&data[..end])),
+                    "split at {split}, end at {end}"
+                );
+            }
+        }
+    }
+}
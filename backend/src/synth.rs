@@ -0,0 +1,1244 @@
+//! Statistical synthetic data generation.
+//!
+//! A [TableModel] is fitted from a source table by estimating the marginal
+//! distribution of every column independently: a histogram for numeric
+//! columns, value frequencies for categorical ones, and the rate of missing
+//! values. New rows are sampled from these marginals. Identifier columns get
+//! fresh identifiers and personal or free-form values are replaced by random
+//! strings of the same shape, so no source value of those columns is copied.
+//!
+//! Optionally, dependence between numeric and categorical columns is kept with
+//! a Gaussian copula: correlated standard normal variates are drawn using the
+//! Cholesky factor of a correlation matrix derived from the Spearman rank
+//! correlations of the source, mapped to uniforms with the normal CDF and fed
+//! through the inverse CDF of each column's marginal.
+//!
+//! With a privacy budget epsilon, the fitted statistics are derived from
+//! noisy counts:
+//!
+//! - the range of a numeric column is read from a noisy histogram of value
+//!   magnitudes over a fixed grid of powers of two, values are clipped to it,
+//!   and its decimal places come from a noisy histogram as well;
+//! - categories whose noisy count stays below a threshold are dropped, so a
+//!   value held by a few rows is released with probability at most [DELTA];
+//! - the copula is fitted to sums of products of normal scores under the
+//!   noisy marginals, clipped to [NORMAL_SCORE_BOUND] so that every row
+//!   changes each sum by a bounded amount;
+//! - masked columns use fixed templates instead of source values.
+//!
+//! Given how each column is modelled, the fit is therefore (epsilon,
+//! [DELTA])-differentially private with respect to adding or removing one
+//! source row. How a column is modelled is not private: its type and whether
+//! it is masked as personal data are detected from the source values without
+//! noise, so a single row can change them, for instance by turning a numeric
+//! column categorical or by making a column look like email addresses. The
+//! guarantee does not cover the schema of the output.
+
+use crate::csv::Table;
+use crate::profile::{
+    detect_data_type, detect_pii, is_missing, ordinal_sort_by_key, SpearmanMatrix,
+};
+use crate::rng::Rng;
+use std::collections::HashMap;
+
+/// Upper bound on the number of histogram bins of a numeric column.
+const MAX_BINS: usize = 50;
+/// Number of source values kept as templates for masked columns.
+const MAX_SHAPES: usize = 100;
+/// Upper bound on the decimal places reproduced for numeric columns.
+const MAX_DECIMALS: usize = 6;
+/// Step by which a correlation matrix that is not positive definite is shrunk
+/// towards the identity before retrying its Cholesky decomposition.
+const SHRINKAGE_STEP: f64 = 0.05;
+/// Privacy budgets of the `privacy_level`s "low", "medium" and "high".
+const PRIVACY_LEVELS: [(&str, f64); 3] = [("low", 8.0), ("medium", 2.0), ("high", 0.5)];
+/// Probability with which a private fit may release a category that only
+/// one row holds, beyond what epsilon allows.
+const DELTA: f64 = 1e-6;
+/// Magnitude histograms bounding numeric columns have buckets `[0, 1)`,
+/// `[1, 2)`, `[2, 4)`, ... up to `2^MAX_EXPONENT`, and the same negated.
+const MAX_EXPONENT: usize = 64;
+/// Probability that the noise of some empty bucket of a magnitude or decimal
+/// places histogram reaches the threshold at which the bucket is used.
+const EMPTY_BUCKET_RATE: f64 = 0.05;
+/// Normal scores of private copulas are clipped to this absolute value.
+const NORMAL_SCORE_BOUND: f64 = 3.0;
+
+/// Returns the privacy budget epsilon of a `privacy_level`.
+pub fn privacy_epsilon(privacy_level: &str) -> Option<f64> {
+    PRIVACY_LEVELS
+        .iter()
+        .find(|(level, _)| *level == privacy_level)
+        .map(|(_, epsilon)| *epsilon)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColumnModel {
+    /// Fresh identifiers `SYN_000001`, `SYN_000002`, ... in row order.
+    Identifier,
+    Numeric(NumericModel),
+    Categorical(CategoricalModel),
+    /// Values replaced by random characters of the same class as those of a
+    /// randomly picked template (digits by digits, letters by letters).
+    Masked {
+        shapes: Vec<String>,
+    },
+}
+
+/// Equal-width histogram of a numeric column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumericModel {
+    /// `weights.len() + 1` ascending bin boundaries.
+    pub edges: Vec<f64>,
+    pub weights: Vec<f64>,
+    /// Whether all source values are integers.
+    pub integer: bool,
+    pub decimals: usize,
+}
+
+/// Category frequencies of a column, with categories in ordinal order
+/// (numeric if all of them are numbers, lexicographic otherwise).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CategoricalModel {
+    pub categories: Vec<String>,
+    pub weights: Vec<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnFit {
+    pub name: String,
+    pub model: ColumnModel,
+    /// Probability of emitting an empty value.
+    pub missing_rate: f64,
+}
+
+/// Gaussian copula over the numeric and categorical columns of a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Copula {
+    /// Indices of the coupled columns in [TableModel::columns].
+    pub columns: Vec<usize>,
+    /// Lower-triangular Cholesky factor of the normal correlation matrix.
+    pub cholesky: Vec<Vec<f64>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FitOptions {
+    /// Mask columns that look like personal data even if they are categorical.
+    pub mask_pii: bool,
+    /// Fit a Gaussian copula to preserve correlations between columns.
+    pub preserve_correlations: bool,
+    /// Privacy budget spent on the fitted statistics, but not on the detected
+    /// column types; `None` fits them without noise.
+    pub epsilon: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableModel {
+    pub delimiter: u8,
+    pub columns: Vec<ColumnFit>,
+    /// Present if correlations between columns are preserved.
+    pub copula: Option<Copula>,
+}
+
+impl TableModel {
+    /// Fits per-column marginals, and optionally a copula, to `table`. `rng`
+    /// is only used to draw noise when `options.epsilon` is set.
+    #[cfg(test)]
+    pub fn fit(table: &Table, options: &FitOptions, rng: &mut Rng) -> Self {
+        let mut fit = ModelFit::new(table, options);
+        while !fit.step(table, rng) {}
+        fit.finish()
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &str> {
+        self.columns.iter().map(|column| column.name.as_str())
+    }
+
+    /// Samples the row with 0-based position `index` in the output.
+    pub fn sample_row(&self, index: usize, rng: &mut Rng) -> Vec<String> {
+        let mut uniforms = vec![None; self.columns.len()];
+        if let Some(copula) = &self.copula {
+            for (&column, u) in copula.columns.iter().zip(copula.sample(rng)) {
+                uniforms[column] = Some(u);
+            }
+        }
+        self.columns
+            .iter()
+            .zip(uniforms)
+            .map(|(column, u)| {
+                if rng.chance(column.missing_rate) {
+                    String::new()
+                } else if let Some(u) = u {
+                    column.model.quantile(u)
+                } else {
+                    column.model.sample(index, rng)
+                }
+            })
+            .collect()
+    }
+
+    /// Samples `num_records` rows and returns them with a header row as CSV.
+    #[cfg(test)]
+    pub fn generate(&self, num_records: usize, rng: &mut Rng) -> String {
+        let mut writer = crate::csv::Writer::new(self.delimiter);
+        writer.write_record(self.headers());
+        for index in 0..num_records {
+            writer.write_record(self.sample_row(index, rng));
+        }
+        writer.finish()
+    }
+}
+
+/// A [TableModel::fit] done one column or one pair of columns per
+/// [ModelFit::step], so that the work done at a time stays bounded.
+pub struct ModelFit {
+    delimiter: u8,
+    options: FitOptions,
+    columns: Vec<ColumnFit>,
+    stage: FitStage,
+}
+
+enum FitStage {
+    /// Fitting the marginal of the next column.
+    Marginals,
+    /// Adding noise to the marginal of column `next`; `correlations` holds
+    /// the columns to couple and the budget left for their correlations.
+    Privatizing {
+        scale: f64,
+        threshold: f64,
+        next: usize,
+        correlations: Option<(Vec<usize>, f64)>,
+    },
+    /// Encoding the next `coupled` column for its rank correlations.
+    Encoding {
+        coupled: Vec<usize>,
+        encoded: Vec<Vec<Option<f64>>>,
+    },
+    Ranking {
+        coupled: Vec<usize>,
+        spearman: SpearmanMatrix,
+    },
+    /// Computing the normal scores of the next `coupled` column under its
+    /// private marginal.
+    Scoring {
+        coupled: Vec<usize>,
+        scores: Vec<Vec<f64>>,
+        epsilon: f64,
+    },
+    /// Adding up the products of the scores of columns `next`, with noise of
+    /// `scale`. As scores are clipped, a row changes each sum by at most
+    /// `NORMAL_SCORE_BOUND^2`.
+    Summing {
+        coupled: Vec<usize>,
+        scores: Vec<Vec<f64>>,
+        sums: Vec<Vec<f64>>,
+        scale: f64,
+        next: (usize, usize),
+    },
+    Done(Option<Copula>),
+}
+
+impl ModelFit {
+    pub fn new(table: &Table, options: &FitOptions) -> Self {
+        Self {
+            delimiter: table.delimiter,
+            options: options.clone(),
+            columns: Vec::new(),
+            stage: FitStage::Marginals,
+        }
+    }
+
+    /// Does the next part of fitting `table`, the same table as passed to
+    /// [ModelFit::new]. Returns true once the model is fitted.
+    pub fn step(&mut self, table: &Table, rng: &mut Rng) -> bool {
+        let stage = std::mem::replace(&mut self.stage, FitStage::Done(None));
+        self.stage = match stage {
+            FitStage::Marginals if self.columns.len() < table.headers.len() => {
+                let idx = self.columns.len();
+                let private = self.options.epsilon.is_some();
+                let values = column_values(table, idx);
+                let name = &table.headers[idx];
+                let column = fit_column(name, &values, self.options.mask_pii, private);
+                self.columns.push(column);
+                FitStage::Marginals
+            }
+            FitStage::Marginals => self.fitted_marginals(),
+            FitStage::Privatizing {
+                scale,
+                threshold,
+                next,
+                correlations,
+            } if next < self.columns.len() => {
+                let values = column_values(table, next);
+                self.columns[next].privatize(&values, scale, threshold, rng);
+                FitStage::Privatizing {
+                    scale,
+                    threshold,
+                    next: next + 1,
+                    correlations,
+                }
+            }
+            FitStage::Privatizing { correlations, .. } => match correlations {
+                Some((coupled, epsilon)) => FitStage::Scoring {
+                    coupled,
+                    scores: Vec::new(),
+                    epsilon,
+                },
+                None => FitStage::Done(None),
+            },
+            FitStage::Encoding {
+                coupled,
+                mut encoded,
+            } => {
+                let idx = coupled[encoded.len()];
+                let column = self.columns[idx].model.encode(&column_values(table, idx));
+                encoded.push(column.expect("coupled columns can be encoded"));
+                if encoded.len() < coupled.len() {
+                    FitStage::Encoding { coupled, encoded }
+                } else {
+                    FitStage::Ranking {
+                        coupled,
+                        spearman: SpearmanMatrix::new(encoded),
+                    }
+                }
+            }
+            FitStage::Ranking {
+                coupled,
+                mut spearman,
+            } => {
+                if spearman.step() {
+                    let normal = normal_correlations(&spearman.finish());
+                    FitStage::Done(Some(Copula::new(coupled, &normal)))
+                } else {
+                    FitStage::Ranking { coupled, spearman }
+                }
+            }
+            FitStage::Scoring {
+                coupled,
+                mut scores,
+                epsilon,
+            } => {
+                let idx = coupled[scores.len()];
+                scores.push(
+                    self.columns[idx]
+                        .model
+                        .normal_scores(&column_values(table, idx)),
+                );
+                if scores.len() < coupled.len() {
+                    FitStage::Scoring {
+                        coupled,
+                        scores,
+                        epsilon,
+                    }
+                } else {
+                    let n = coupled.len();
+                    let sums_count = (n * (n + 1) / 2) as f64;
+                    FitStage::Summing {
+                        coupled,
+                        scores,
+                        sums: vec![vec![0.0; n]; n],
+                        scale: sums_count * NORMAL_SCORE_BOUND * NORMAL_SCORE_BOUND / epsilon,
+                        next: (0, 0),
+                    }
+                }
+            }
+            FitStage::Summing {
+                coupled,
+                scores,
+                mut sums,
+                scale,
+                next: (i, j),
+            } => {
+                let products: f64 = scores[i].iter().zip(&scores[j]).map(|(a, b)| a * b).sum();
+                let noisy = products + rng.laplace(scale);
+                sums[i][j] = noisy;
+                sums[j][i] = noisy;
+                let next = if j < i { (i, j + 1) } else { (i + 1, 0) };
+                if next.0 < coupled.len() {
+                    FitStage::Summing {
+                        coupled,
+                        scores,
+                        sums,
+                        scale,
+                        next,
+                    }
+                } else {
+                    let normal = correlations_from_sums(&sums);
+                    FitStage::Done(Some(Copula::new(coupled, &normal)))
+                }
+            }
+            done @ FitStage::Done(_) => done,
+        };
+        matches!(self.stage, FitStage::Done(_))
+    }
+
+    /// Stage that follows fitting all marginals.
+    fn fitted_marginals(&self) -> FitStage {
+        let coupled: Vec<usize> = (0..self.columns.len())
+            .filter(|&idx| self.columns[idx].model.is_couplable())
+            .collect();
+        let correlate = self.options.preserve_correlations && coupled.len() >= 2;
+        match self.options.epsilon {
+            None if correlate => FitStage::Encoding {
+                coupled,
+                encoded: Vec::new(),
+            },
+            None => FitStage::Done(None),
+            Some(epsilon) => {
+                // The budget is split evenly between the marginals and the
+                // correlations.
+                let marginal_epsilon = if correlate { epsilon / 2.0 } else { epsilon };
+                let (scale, threshold) = marginal_noise(&self.columns, marginal_epsilon);
+                FitStage::Privatizing {
+                    scale,
+                    threshold,
+                    next: 0,
+                    correlations: correlate.then(|| (coupled, epsilon / 2.0)),
+                }
+            }
+        }
+    }
+
+    /// Returns the fitted model; panics unless [ModelFit::step] returned true.
+    pub fn finish(self) -> TableModel {
+        let FitStage::Done(copula) = self.stage else {
+            panic!("model fit is unfinished");
+        };
+        TableModel {
+            delimiter: self.delimiter,
+            columns: self.columns,
+            copula,
+        }
+    }
+}
+
+fn column_values(table: &Table, idx: usize) -> Vec<&str> {
+    table.rows.iter().map(|row| row[idx].trim()).collect()
+}
+
+impl ColumnModel {
+    fn sample(&self, index: usize, rng: &mut Rng) -> String {
+        match self {
+            ColumnModel::Identifier => format!("SYN_{:06}", index + 1),
+            ColumnModel::Numeric(model) => model.format(model.sample(rng)),
+            ColumnModel::Categorical(model) => rng
+                .weighted_index(&model.weights)
+                .map(|i| model.categories[i].clone())
+                .unwrap_or_default(),
+            ColumnModel::Masked { shapes } => {
+                if shapes.is_empty() {
+                    return String::new();
+                }
+                mask(&shapes[rng.below(shapes.len())], rng)
+            }
+        }
+    }
+}
+
+impl ColumnModel {
+    fn is_couplable(&self) -> bool {
+        matches!(self, ColumnModel::Numeric(_) | ColumnModel::Categorical(_))
+    }
+
+    /// Number of noisy counts of a private fit that one row adds to, at most.
+    fn row_counts(&self) -> f64 {
+        match self {
+            // A magnitude, a number of decimal places and a bin.
+            ColumnModel::Numeric(_) => 3.0,
+            _ => 1.0,
+        }
+    }
+
+    /// Normal scores of `values` under the column's marginal, clipped to
+    /// [NORMAL_SCORE_BOUND]; 0 for values the marginal does not contain.
+    fn normal_scores(&self, values: &[&str]) -> Vec<f64> {
+        let probabilities: Vec<Option<f64>> = match self {
+            ColumnModel::Numeric(model) => values
+                .iter()
+                .map(|v| {
+                    v.parse::<f64>()
+                        .ok()
+                        .filter(|x| x.is_finite())
+                        .map(|x| model.cdf(x))
+                })
+                .collect(),
+            ColumnModel::Categorical(model) => {
+                let index = model.index();
+                values
+                    .iter()
+                    .map(|v| index.get(v).map(|&i| model.mid_cdf(i)))
+                    .collect()
+            }
+            _ => vec![None; values.len()],
+        };
+        probabilities
+            .into_iter()
+            .map(|p| p.map_or(0.0, clipped_normal_quantile))
+            .collect()
+    }
+
+    /// Numeric codes of `values` for correlation analysis, or `None` if the
+    /// column is not coupled by a copula.
+    fn encode(&self, values: &[&str]) -> Option<Vec<Option<f64>>> {
+        match self {
+            ColumnModel::Numeric(_) => Some(
+                values
+                    .iter()
+                    .map(|v| v.parse::<f64>().ok().filter(|x| x.is_finite()))
+                    .collect(),
+            ),
+            ColumnModel::Categorical(model) => {
+                let index = model.index();
+                Some(
+                    values
+                        .iter()
+                        .map(|v| index.get(v).map(|&i| i as f64))
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// Value at cumulative probability `u` of a numeric or categorical column.
+    fn quantile(&self, u: f64) -> String {
+        match self {
+            ColumnModel::Numeric(model) => model.format(model.quantile(u)),
+            ColumnModel::Categorical(model) => model.quantile(u).to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Laplace noise scale and category threshold with which the marginals of
+/// `columns` are privatized, spending `epsilon` on all of them.
+fn marginal_noise(columns: &[ColumnFit], epsilon: f64) -> (f64, f64) {
+    let sensitivity: f64 = columns.iter().map(|column| column.model.row_counts()).sum();
+    let scale = sensitivity / epsilon;
+    // A row adds at most one category per categorical column, and each of
+    // them is released with probability at most DELTA / categorical.
+    let categorical = columns
+        .iter()
+        .filter(|column| matches!(column.model, ColumnModel::Categorical(_)))
+        .count();
+    let threshold = 1.0 + scale * (categorical.max(1) as f64 / (2.0 * DELTA)).ln();
+    (scale, threshold)
+}
+
+impl ColumnFit {
+    /// Refits the column to `values` from counts with Laplace noise of
+    /// `scale`, keeping categories whose noisy count reaches
+    /// `category_threshold`. Every row adds to at most
+    /// [ColumnModel::row_counts] counts.
+    fn privatize(&mut self, values: &[&str], scale: f64, category_threshold: f64, rng: &mut Rng) {
+        let present: Vec<&str> = values.iter().copied().filter(|v| !is_missing(v)).collect();
+        let missing = (values.len() - present.len()) as f64;
+        let noisy_missing = (missing + rng.laplace(scale)).max(0.0);
+        let noisy_present = match &mut self.model {
+            ColumnModel::Numeric(model) => {
+                *model = NumericModel::fit_private(&present, scale, rng);
+                model.weights.iter().sum()
+            }
+            ColumnModel::Categorical(model) => {
+                model.privatize(scale, category_threshold, rng);
+                model.weights.iter().sum()
+            }
+            _ => (present.len() as f64 + rng.laplace(scale)).max(0.0),
+        };
+        if let ColumnModel::Numeric(NumericModel { weights, .. })
+        | ColumnModel::Categorical(CategoricalModel { weights, .. }) = &mut self.model
+        {
+            if noisy_present == 0.0 {
+                weights.iter_mut().for_each(|w| *w = 1.0);
+            }
+        }
+        self.missing_rate = if noisy_missing + noisy_present > 0.0 {
+            noisy_missing / (noisy_missing + noisy_present)
+        } else {
+            0.0
+        };
+    }
+}
+
+/// Correlations of a normal distribution with the given rank correlations.
+fn normal_correlations(spearman: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    spearman
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .enumerate()
+                .map(|(j, &rho)| {
+                    if i == j {
+                        1.0
+                    } else {
+                        2.0 * (std::f64::consts::PI * rho / 6.0).sin()
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Normal correlations estimated from `sums` of products of normal scores,
+/// which need not be consistent as they are noisy.
+fn correlations_from_sums(sums: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let n = sums.len();
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| {
+                    if i == j {
+                        1.0
+                    } else {
+                        let norm = (sums[i][i].max(1.0) * sums[j][j].max(1.0)).sqrt();
+                        (sums[i][j] / norm).clamp(-1.0, 1.0)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+impl Copula {
+    /// Copula of `columns` with the given normal correlation matrix.
+    fn new(columns: Vec<usize>, normal: &[Vec<f64>]) -> Self {
+        Self {
+            columns,
+            cholesky: shrunk_cholesky(normal),
+        }
+    }
+
+    /// Draws one uniform in `[0, 1]` per coupled column.
+    fn sample(&self, rng: &mut Rng) -> Vec<f64> {
+        let z: Vec<f64> = (0..self.columns.len()).map(|_| rng.normal()).collect();
+        self.cholesky
+            .iter()
+            .map(|row| normal_cdf(row.iter().zip(&z).map(|(l, z)| l * z).sum()))
+            .collect()
+    }
+}
+
+/// Cholesky factor of `matrix`, shrinking it towards the identity until it is
+/// positive definite.
+fn shrunk_cholesky(matrix: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let mut shrinkage: f64 = 0.0;
+    loop {
+        let shrunk: Vec<Vec<f64>> = matrix
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                row.iter()
+                    .enumerate()
+                    .map(|(j, &x)| if i == j { x } else { x * (1.0 - shrinkage) })
+                    .collect()
+            })
+            .collect();
+        if let Some(factor) = cholesky(&shrunk) {
+            return factor;
+        }
+        shrinkage = (shrinkage + SHRINKAGE_STEP).min(1.0);
+    }
+}
+
+fn cholesky(matrix: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = matrix.len();
+    let mut l = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: f64 = (0..j).map(|k| l[i][k] * l[j][k]).sum();
+            if i == j {
+                let d = matrix[i][i] - sum;
+                if d <= 1e-10 {
+                    return None;
+                }
+                l[i][i] = d.sqrt();
+            } else {
+                l[i][j] = (matrix[i][j] - sum) / l[j][j];
+            }
+        }
+    }
+    Some(l)
+}
+
+/// Standard normal CDF, using the Abramowitz-Stegun approximation of erf
+/// (absolute error below 1.5e-7).
+fn normal_cdf(x: f64) -> f64 {
+    let t = x.abs() / std::f64::consts::SQRT_2;
+    let k = 1.0 / (1.0 + 0.327_591_1 * t);
+    let poly = k
+        * (0.254_829_592
+            + k * (-0.284_496_736
+                + k * (1.421_413_741 + k * (-1.453_152_027 + k * 1.061_405_429))));
+    let erf = 1.0 - poly * (-t * t).exp();
+    if x >= 0.0 {
+        0.5 * (1.0 + erf)
+    } else {
+        0.5 * (1.0 - erf)
+    }
+}
+
+/// Inverse of [normal_cdf], found by bisection within [NORMAL_SCORE_BOUND].
+fn clipped_normal_quantile(p: f64) -> f64 {
+    let (mut low, mut high) = (-NORMAL_SCORE_BOUND, NORMAL_SCORE_BOUND);
+    for _ in 0..40 {
+        let mid = (low + high) / 2.0;
+        if normal_cdf(mid) < p {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.0
+}
+
+/// Index of the first weight at which the cumulative sum reaches `u` of the
+/// total, together with the fraction of that weight needed to reach it.
+fn cumulative_position(weights: &[f64], u: f64) -> (usize, f64) {
+    let total: f64 = weights.iter().sum();
+    let mut target = u.clamp(0.0, 1.0) * total;
+    for (i, &weight) in weights.iter().enumerate() {
+        if target <= weight && weight > 0.0 {
+            return (i, target / weight);
+        }
+        target -= weight;
+    }
+    (weights.iter().rposition(|&w| w > 0.0).unwrap_or(0), 1.0)
+}
+
+impl CategoricalModel {
+    pub fn quantile(&self, u: f64) -> &str {
+        if self.categories.is_empty() {
+            return "";
+        }
+        &self.categories[cumulative_position(&self.weights, u).0]
+    }
+
+    /// Cumulative probability up to the middle of category `index`.
+    fn mid_cdf(&self, index: usize) -> f64 {
+        let total: f64 = self.weights.iter().sum();
+        if total <= 0.0 {
+            return 0.5;
+        }
+        let below: f64 = self.weights[..index].iter().sum();
+        (below + self.weights[index] / 2.0) / total
+    }
+
+    /// Position of each category in [CategoricalModel::categories].
+    fn index(&self) -> HashMap<&str, usize> {
+        self.categories
+            .iter()
+            .enumerate()
+            .map(|(i, category)| (category.as_str(), i))
+            .collect()
+    }
+
+    /// Adds Laplace noise of `scale` to the category counts and drops the
+    /// categories whose noisy count is below `threshold`.
+    fn privatize(&mut self, scale: f64, threshold: f64, rng: &mut Rng) {
+        let (categories, weights) = self
+            .categories
+            .drain(..)
+            .zip(&self.weights)
+            .map(|(category, &weight)| (category, weight + rng.laplace(scale)))
+            .filter(|&(_, noisy)| noisy >= threshold)
+            .unzip();
+        self.categories = categories;
+        self.weights = weights;
+    }
+}
+
+impl NumericModel {
+    fn fit(numbers: &[f64], texts: &[&str]) -> Self {
+        let min = numbers.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = numbers.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let bins = if max > min {
+            ((numbers.len() as f64).sqrt().ceil() as usize).clamp(1, MAX_BINS)
+        } else {
+            1
+        };
+        let width = (max - min) / bins as f64;
+        let edges = (0..=bins).map(|i| min + width * i as f64).collect();
+        let mut weights = vec![0.0; bins];
+        for &x in numbers {
+            let bin = if width > 0.0 {
+                (((x - min) / width) as usize).min(bins - 1)
+            } else {
+                0
+            };
+            weights[bin] += 1.0;
+        }
+        Self {
+            edges,
+            weights,
+            integer: numbers.iter().all(|x| x.fract() == 0.0),
+            decimals: texts
+                .iter()
+                .map(|t| decimal_places(t))
+                .max()
+                .unwrap_or(0)
+                .min(MAX_DECIMALS),
+        }
+    }
+
+    /// Fits a histogram to the numbers among `values` from counts with
+    /// Laplace noise of `scale`. Each number adds to three counts: of its
+    /// magnitude, which bounds the histogram, of its decimal places, and of
+    /// its bin.
+    fn fit_private(values: &[&str], scale: f64, rng: &mut Rng) -> Self {
+        let (numbers, texts): (Vec<f64>, Vec<&str>) = values
+            .iter()
+            .filter_map(|v| Some((v.parse::<f64>().ok().filter(|x| x.is_finite())?, *v)))
+            .unzip();
+
+        let mut magnitudes = vec![0.0; 2 * (MAX_EXPONENT + 1)];
+        for &x in &numbers {
+            magnitudes[magnitude_bucket(x)] += 1.0;
+        }
+        let magnitudes = noisy_counts(&magnitudes, scale, rng);
+        let threshold = scale * (magnitudes.len() as f64 / (2.0 * EMPTY_BUCKET_RATE)).ln();
+        let used = |count: f64| count >= threshold;
+        let largest = || {
+            (0..magnitudes.len())
+                .max_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b]))
+                .unwrap_or(0)
+        };
+        let first = magnitudes
+            .iter()
+            .position(|&c| used(c))
+            .unwrap_or_else(largest);
+        let last = magnitudes
+            .iter()
+            .rposition(|&c| used(c))
+            .unwrap_or_else(largest);
+        let (min, max) = (bucket_range(first).0, bucket_range(last).1);
+
+        let mut decimals = vec![0.0; MAX_DECIMALS + 1];
+        for text in &texts {
+            decimals[decimal_places(text).min(MAX_DECIMALS)] += 1.0;
+        }
+        let decimals = noisy_counts(&decimals, scale, rng);
+        let decimals = decimals.iter().rposition(|&c| used(c)).unwrap_or(0);
+
+        let noisy_total: f64 = magnitudes.iter().sum();
+        let bins = (noisy_total.max(1.0).sqrt().ceil() as usize).clamp(1, MAX_BINS);
+        let width = (max - min) / bins as f64;
+        let edges = (0..=bins).map(|i| min + width * i as f64).collect();
+        let mut weights = vec![0.0; bins];
+        for &x in &numbers {
+            let bin = (((x.clamp(min, max) - min) / width) as usize).min(bins - 1);
+            weights[bin] += 1.0;
+        }
+        Self {
+            edges,
+            weights: noisy_counts(&weights, scale, rng),
+            integer: decimals == 0,
+            decimals,
+        }
+    }
+
+    /// Cumulative probability of `x`, linear within each bin.
+    fn cdf(&self, x: f64) -> f64 {
+        let total: f64 = self.weights.iter().sum();
+        if total <= 0.0 {
+            return 0.5;
+        }
+        let mut below = 0.0;
+        for (bin, &weight) in self.weights.iter().enumerate() {
+            let (low, high) = (self.edges[bin], self.edges[bin + 1]);
+            if x >= high {
+                below += weight;
+            } else {
+                if x > low {
+                    below += weight * (x - low) / (high - low);
+                }
+                break;
+            }
+        }
+        below / total
+    }
+
+    /// Picks a bin by weight and a uniform value within it.
+    pub fn sample(&self, rng: &mut Rng) -> f64 {
+        let bin = rng.weighted_index(&self.weights).unwrap_or(0);
+        let (low, high) = (self.edges[bin], self.edges[bin + 1]);
+        low + (high - low) * rng.next_f64()
+    }
+
+    /// Inverse CDF, linear within each bin.
+    pub fn quantile(&self, u: f64) -> f64 {
+        let (bin, fraction) = cumulative_position(&self.weights, u);
+        let (low, high) = (self.edges[bin], self.edges[bin + 1]);
+        low + (high - low) * fraction
+    }
+
+    pub fn format(&self, x: f64) -> String {
+        if self.integer {
+            format!("{}", x.round() as i64)
+        } else {
+            format!("{:.*}", self.decimals, x)
+        }
+    }
+}
+
+fn decimal_places(text: &str) -> usize {
+    text.split_once('.').map_or(0, |(_, frac)| frac.len())
+}
+
+/// `counts` with Laplace noise of `scale` added, rounded up to zero.
+fn noisy_counts(counts: &[f64], scale: f64, rng: &mut Rng) -> Vec<f64> {
+    counts
+        .iter()
+        .map(|count| (count + rng.laplace(scale)).max(0.0))
+        .collect()
+}
+
+/// Index of the magnitude histogram bucket of `x`; see [MAX_EXPONENT].
+fn magnitude_bucket(x: f64) -> usize {
+    let exponent = if x.abs() < 1.0 {
+        0
+    } else {
+        (x.abs().log2().floor() as usize + 1).min(MAX_EXPONENT)
+    };
+    if x < 0.0 {
+        MAX_EXPONENT - exponent
+    } else {
+        MAX_EXPONENT + 1 + exponent
+    }
+}
+
+/// Lower and upper end of magnitude histogram bucket `index`.
+fn bucket_range(index: usize) -> (f64, f64) {
+    let magnitude = |exponent: usize| match exponent {
+        0 => (0.0, 1.0),
+        _ => (2f64.powi(exponent as i32 - 1), 2f64.powi(exponent as i32)),
+    };
+    if index <= MAX_EXPONENT {
+        let (low, high) = magnitude(MAX_EXPONENT - index);
+        (-high, -low)
+    } else {
+        magnitude(index - MAX_EXPONENT - 1)
+    }
+}
+
+/// Fits the marginal of one column.
+fn fit_column(name: &str, values: &[&str], mask_pii: bool, private: bool) -> ColumnFit {
+    let present: Vec<&str> = values.iter().copied().filter(|v| !is_missing(v)).collect();
+    let missing_rate = if values.is_empty() {
+        0.0
+    } else {
+        1.0 - present.len() as f64 / values.len() as f64
+    };
+
+    let data_type = detect_data_type(name, &present);
+    let model = match data_type {
+        "id" => ColumnModel::Identifier,
+        "email" | "phone" | "text" => masked(data_type, &present, private),
+        _ if mask_pii && detect_pii(name, &present) => masked(data_type, &present, private),
+        "numeric" => {
+            let (numbers, texts): (Vec<f64>, Vec<&str>) = present
+                .iter()
+                .filter_map(|v| Some((v.parse::<f64>().ok().filter(|x| x.is_finite())?, *v)))
+                .unzip();
+            if numbers.is_empty() {
+                categorical(&present)
+            } else {
+                ColumnModel::Numeric(NumericModel::fit(&numbers, &texts))
+            }
+        }
+        _ => categorical(&present),
+    };
+    ColumnFit {
+        name: name.to_string(),
+        model,
+        missing_rate,
+    }
+}
+
+fn categorical(values: &[&str]) -> ColumnModel {
+    let mut counts: Vec<(String, f64)> = Vec::new();
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    for value in sorted {
+        match counts.last_mut() {
+            Some((last, count)) if last == value => *count += 1.0,
+            _ => counts.push((value.to_string(), 1.0)),
+        }
+    }
+    ordinal_sort_by_key(&mut counts, |(category, _)| category);
+    let (categories, weights) = counts.into_iter().unzip();
+    ColumnModel::Categorical(CategoricalModel {
+        categories,
+        weights,
+    })
+}
+
+/// Masks a column, keeping source values as templates unless `private`.
+fn masked(data_type: &str, values: &[&str], private: bool) -> ColumnModel {
+    let shapes = if private {
+        vec![template(data_type).to_string()]
+    } else {
+        values
+            .iter()
+            .take(MAX_SHAPES)
+            .map(|v| v.to_string())
+            .collect()
+    };
+    ColumnModel::Masked { shapes }
+}
+
+/// Fixed masking template for values of `data_type`.
+fn template(data_type: &str) -> &'static str {
+    match data_type {
+        "email" => "aaaaaaaa@aaaaaaa.aaa",
+        "phone" => "000-000-0000",
+        "numeric" => "00000",
+        _ => "Aaaaaaaa",
+    }
+}
+
+/// Replaces every ASCII digit and letter of `template` by a random character
+/// of the same class, keeping punctuation such as `@`, `.` and `-`.
+fn mask(template: &str, rng: &mut Rng) -> String {
+    const DIGITS: &[u8] = b"0123456789";
+    const LOWER: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+    const UPPER: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    template
+        .chars()
+        .map(|c| {
+            let class = if c.is_ascii_digit() {
+                DIGITS
+            } else if c.is_ascii_lowercase() {
+                LOWER
+            } else if c.is_ascii_uppercase() {
+                UPPER
+            } else if c.is_alphabetic() {
+                LOWER
+            } else {
+                return c;
+            };
+            class[rng.below(class.len())] as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::CorrelationMatrix;
+
+    fn source() -> Table {
+        let mut csv = String::from("patient_id,age,sex,email\n");
+        for i in 0..200 {
+            let sex = if i % 4 == 0 { "M" } else { "F" };
+            let age = if i % 10 == 0 {
+                String::new()
+            } else {
+                (20 + i % 50).to_string()
+            };
+            csv.push_str(&format!("P{i},{age},{sex},user{i}@example.com\n"));
+        }
+        Table::read(csv.as_bytes())
+    }
+
+    fn fit(table: &Table, mask_pii: bool, preserve_correlations: bool) -> TableModel {
+        let options = FitOptions {
+            mask_pii,
+            preserve_correlations,
+            epsilon: None,
+        };
+        TableModel::fit(table, &options, &mut Rng::seed_from_u64(0))
+    }
+
+    fn generate(seed: u64) -> Table {
+        let model = fit(&source(), true, false);
+        let csv = model.generate(2_000, &mut Rng::seed_from_u64(seed));
+        Table::read(csv.as_bytes())
+    }
+
+    #[test]
+    fn preserves_marginals() {
+        let output = generate(1);
+        assert_eq!(output.headers, ["patient_id", "age", "sex", "email"]);
+        assert_eq!(output.rows.len(), 2_000);
+
+        let ages: Vec<f64> = output
+            .rows
+            .iter()
+            .filter_map(|row| row[1].parse().ok())
+            .collect();
+        let missing = 1.0 - ages.len() as f64 / 2_000.0;
+        assert!((missing - 0.1).abs() < 0.03, "missing rate {}", missing);
+        assert!(ages.iter().all(|&a| (20.0..=69.0).contains(&a)));
+        let mean = ages.iter().sum::<f64>() / ages.len() as f64;
+        assert!((mean - 44.5).abs() < 2.0, "mean age {}", mean);
+
+        let male = output.rows.iter().filter(|row| row[2] == "M").count();
+        assert!((male as f64 / 2_000.0 - 0.25).abs() < 0.03);
+    }
+
+    #[test]
+    fn same_seed_reproduces_output() {
+        let model = fit(&source(), true, true);
+        let run = |seed| model.generate(500, &mut Rng::seed_from_u64(seed));
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn does_not_copy_identifiers_or_personal_data() {
+        let source = source();
+        let output = generate(2);
+        assert_eq!(output.rows[0][0], "SYN_000001");
+        for row in &output.rows {
+            assert!(row[3].contains('@'));
+            assert!(!source.rows.iter().any(|s| s[3] == row[3]));
+        }
+    }
+
+    fn correlated_source() -> Table {
+        let mut csv = String::from("income,spending,tier\n");
+        let mut rng = Rng::seed_from_u64(7);
+        for _ in 0..500 {
+            let income = 30_000.0 + 20_000.0 * rng.normal();
+            let spending = 0.4 * income + 2_000.0 * rng.normal();
+            let tier = if income > 40_000.0 { "high" } else { "low" };
+            csv.push_str(&format!("{:.0},{:.0},{}\n", income, spending, tier));
+        }
+        Table::read(csv.as_bytes())
+    }
+
+    fn spearman(table: &Table) -> Vec<Vec<f64>> {
+        let tiers = ["high", "low"];
+        let encoded: Vec<Vec<Option<f64>>> = (0..3)
+            .map(|idx| {
+                table
+                    .rows
+                    .iter()
+                    .map(|row| match idx {
+                        2 => tiers.iter().position(|t| *t == row[2]).map(|i| i as f64),
+                        _ => row[idx].parse().ok(),
+                    })
+                    .collect()
+            })
+            .collect();
+        CorrelationMatrix::compute(&encoded).spearman
+    }
+
+    #[test]
+    fn copula_preserves_rank_correlations() {
+        let source = correlated_source();
+        let expected = spearman(&source);
+        assert!(expected[0][1] > 0.9);
+
+        let coupled = fit(&source, false, true);
+        assert_eq!(coupled.copula.as_ref().unwrap().columns, [0, 1, 2]);
+        let csv = coupled.generate(2_000, &mut Rng::seed_from_u64(3));
+        let actual = spearman(&Table::read(csv.as_bytes()));
+        let diff = (actual[0][1] - expected[0][1]).abs();
+        assert!(diff < 0.05, "income/spending differs by {}", diff);
+        // Ties of a categorical column attenuate its rank correlation somewhat.
+        for j in 0..2 {
+            assert!(actual[2][j] * expected[2][j] > 0.0);
+            assert!(
+                actual[2][j].abs() > 0.6,
+                "tier correlation {}",
+                actual[2][j]
+            );
+        }
+
+        let independent = fit(&source, false, false);
+        let csv = independent.generate(2_000, &mut Rng::seed_from_u64(3));
+        assert!(spearman(&Table::read(csv.as_bytes()))[0][1].abs() < 0.1);
+    }
+
+    #[test]
+    fn quantiles_invert_marginals() {
+        let model = NumericModel {
+            edges: vec![0.0, 10.0, 20.0],
+            weights: vec![1.0, 3.0],
+            integer: false,
+            decimals: 1,
+        };
+        assert_eq!(model.quantile(0.0), 0.0);
+        assert_eq!(model.quantile(0.25), 10.0);
+        assert_eq!(model.quantile(0.625), 15.0);
+        assert_eq!(model.quantile(1.0), 20.0);
+        assert!((normal_cdf(0.0) - 0.5).abs() < 1e-7);
+        assert!((normal_cdf(1.96) - 0.975).abs() < 1e-4);
+    }
+
+    #[test]
+    fn private_fit_adds_noise_but_keeps_marginals() {
+        assert_eq!(privacy_epsilon("medium"), Some(2.0));
+        assert_eq!(privacy_epsilon("extreme"), None);
+
+        let source = correlated_source();
+        let options = FitOptions {
+            mask_pii: true,
+            preserve_correlations: true,
+            epsilon: privacy_epsilon("medium"),
+        };
+        let private = TableModel::fit(&source, &options, &mut Rng::seed_from_u64(5));
+        assert_eq!(
+            private,
+            TableModel::fit(&source, &options, &mut Rng::seed_from_u64(5))
+        );
+        assert_ne!(private, fit(&source, true, true));
+
+        let csv = private.generate(2_000, &mut Rng::seed_from_u64(6));
+        let output = Table::read(csv.as_bytes());
+        let high = output.rows.iter().filter(|row| row[2] == "high").count();
+        let expected = source.rows.iter().filter(|row| row[2] == "high").count();
+        let diff = (high as f64 / 2_000.0 - expected as f64 / 500.0).abs();
+        assert!(diff < 0.05, "tier share differs by {}", diff);
+        assert!(spearman(&output)[0][1] > 0.5);
+    }
+
+    #[test]
+    fn private_fit_does_not_use_source_values_as_templates() {
+        let options = FitOptions {
+            mask_pii: true,
+            preserve_correlations: false,
+            epsilon: Some(1.0),
+        };
+        let model = TableModel::fit(&source(), &options, &mut Rng::seed_from_u64(1));
+        assert_eq!(
+            model.columns[3].model,
+            ColumnModel::Masked {
+                shapes: vec!["aaaaaaaa@aaaaaaa.aaa".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn private_fit_hides_rare_categories_and_outliers() {
+        let mut csv = String::from("age,city\n");
+        for i in 0..999 {
+            csv.push_str(&format!("{},Springfield\n", 20 + i % 50));
+        }
+        csv.push_str("123456,Shelbyville\n");
+        let options = FitOptions {
+            mask_pii: false,
+            preserve_correlations: true,
+            epsilon: Some(1.0),
+        };
+        let model = TableModel::fit(
+            &Table::read(csv.as_bytes()),
+            &options,
+            &mut Rng::seed_from_u64(2),
+        );
+        let ColumnModel::Numeric(age) = &model.columns[0].model else {
+            panic!("age is not numeric");
+        };
+        // Bounds are powers of two around the bulk of the values.
+        assert_eq!(age.edges[0], 16.0);
+        assert_eq!(*age.edges.last().unwrap(), 128.0);
+        assert!(age.integer);
+        let ColumnModel::Categorical(city) = &model.columns[1].model else {
+            panic!("city is not categorical");
+        };
+        assert_eq!(city.categories, ["Springfield"]);
+        assert!(model.copula.is_some());
+    }
+}
@@ -0,0 +1,86 @@
+//! System API used by the canister methods. Outside a canister, as in unit
+//! tests, the caller and the time are set by the test, timers never fire and
+//! every query of the instruction counter advances it by an amount set by the
+//! test.
+
+use candid::Principal;
+use ic_cdk_timers::TimerId;
+use std::time::Duration;
+
+#[cfg(not(test))]
+pub fn msg_caller() -> Principal {
+    ic_cdk::api::msg_caller()
+}
+
+/// Current time in nanoseconds since the epoch.
+#[cfg(not(test))]
+pub fn time() -> u64 {
+    ic_cdk::api::time()
+}
+
+/// Number of instructions executed in the current message.
+#[cfg(not(test))]
+pub fn instruction_counter() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+#[cfg(not(test))]
+pub fn set_timer(delay: Duration, func: impl FnOnce() + 'static) -> TimerId {
+    ic_cdk_timers::set_timer(delay, func)
+}
+
+#[cfg(not(test))]
+pub fn clear_timer(timer_id: TimerId) {
+    ic_cdk_timers::clear_timer(timer_id)
+}
+
+#[cfg(test)]
+pub use fake::*;
+
+#[cfg(test)]
+mod fake {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        static CALLER: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+        static TIME: Cell<u64> = const { Cell::new(1) };
+        static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        static INSTRUCTIONS_PER_QUERY: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub fn msg_caller() -> Principal {
+        CALLER.with_borrow(|caller| *caller)
+    }
+
+    pub fn time() -> u64 {
+        TIME.with(Cell::get)
+    }
+
+    pub fn instruction_counter() -> u64 {
+        let count = INSTRUCTIONS.with(Cell::get);
+        INSTRUCTIONS.with(|current| current.set(count + INSTRUCTIONS_PER_QUERY.with(Cell::get)));
+        count
+    }
+
+    pub fn set_timer(_delay: Duration, _func: impl FnOnce() + 'static) -> TimerId {
+        TimerId::default()
+    }
+
+    pub fn clear_timer(_timer_id: TimerId) {}
+
+    pub fn set_caller(caller: Principal) {
+        CALLER.with_borrow_mut(|current| *current = caller);
+    }
+
+    pub fn set_time(time: u64) {
+        TIME.with(|current| current.set(time));
+    }
+
+    /// Starts counting the instructions of a new message, each query of the
+    /// counter advancing it by `per_query`.
+    pub fn start_message(per_query: u64) {
+        INSTRUCTIONS.with(|current| current.set(0));
+        INSTRUCTIONS_PER_QUERY.with(|current| current.set(per_query));
+    }
+}
@@ -1,5 +1,6 @@
 import type { BackendActor } from './actor';
 import { get, set } from 'idb-keyval';
+import { unwrap } from './result';
 
 // Usage of the imported bindings only works if the respective .wasm was loaded, which is done in main.ts.
 // See also https://github.com/rollup/plugins/tree/master/packages/wasm#using-with-wasm-bindgen-and-wasm-pack
@@ -60,10 +61,10 @@ export class CryptoService {
     if (!await get([note_id.toString(), owner])) {
       const tsk = vetkd.TransportSecretKey.random();
 
-      const ek_bytes_hex = await this.actor.encrypted_symmetric_key_for_note(note_id, tsk.publicKeyBytes());
+      const ek_bytes_hex = unwrap(await this.actor.encrypted_symmetric_key_for_note(note_id, tsk.publicKeyBytes()));
       const encryptedVetKey = vetkd.EncryptedVetKey.deserialize(hex_decode(ek_bytes_hex));
 
-      const pk_bytes_hex = await this.actor.symmetric_key_verification_key_for_note();
+      const pk_bytes_hex = unwrap(await this.actor.symmetric_key_verification_key_for_note());
       const dpk = vetkd.DerivedPublicKey.deserialize(hex_decode(pk_bytes_hex));
 
       const note_id_bytes: Uint8Array = bigintTo128BitBigEndianUint8Array(note_id);
@@ -1,4 +1,4 @@
-import type { EncryptedNote } from '../lib/backend';
+import type { NoteListing } from '../declarations/encrypted_notes/encrypted_notes.did';
 import type { CryptoService } from './crypto';
 import type { Principal } from '@dfinity/principal';
 
@@ -31,6 +31,14 @@ export function noteFromContent(content: string, tags: string[], self_principal:
   };
 }
 
+/** A note with its content encrypted, ready to be stored. */
+export type EncryptedNote = {
+  id: bigint;
+  encrypted_text: string;
+  owner: string;
+  users: string[];
+};
+
 export async function serialize(
   note: NoteModel,
   cryptoService: CryptoService
@@ -55,16 +63,21 @@ export async function serialize(
   };
 }
 
+/** Text of a note listed with its content; empty if the content was not requested. */
+export function noteText(note: NoteListing): string {
+  return note.encrypted_text[0] ?? '';
+}
+
 export async function deserialize(
-  enote: EncryptedNote,
+  enote: NoteListing,
   cryptoService: CryptoService
 ): Promise<NoteModel> {
-  const serializedNote = await cryptoService.decryptWithNoteKey(enote.id, enote.owner, enote.encrypted_text);
+  const serializedNote = await cryptoService.decryptWithNoteKey(enote.id, enote.owner, noteText(enote));
   const deserializedNote: SerializableNoteModel = JSON.parse(serializedNote);
   return {
     id: enote.id,
     owner: enote.owner,
-    users: enote.users,
+    users: enote.users.map((share) => share.user),
     ...deserializedNote,
   };
 }
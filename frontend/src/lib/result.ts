@@ -0,0 +1,12 @@
+import type { Error as BackendError } from '../declarations/encrypted_notes/encrypted_notes.did';
+
+export type BackendResult<T> = { Ok: T } | { Err: BackendError };
+
+/** Returns the value of a successful backend call and throws its error otherwise. */
+export function unwrap<T>(result: BackendResult<T>): T {
+  if ('Err' in result) {
+    const [kind, message] = Object.entries(result.Err)[0];
+    throw new Error(`${kind}: ${message}`);
+  }
+  return result.Ok;
+}
@@ -1,8 +1,9 @@
 import { writable } from 'svelte/store';
 import type { BackendActor } from '../lib/actor';
-import type { EncryptedNote } from '../lib/backend';
+import type { NoteListing } from '../declarations/encrypted_notes/encrypted_notes.did';
 import type { CryptoService } from '../lib/crypto';
-import { deserialize, NoteModel, serialize } from '../lib/note';
+import { deserialize, NoteModel, noteText, serialize } from '../lib/note';
+import { unwrap } from '../lib/result';
 import { auth } from './auth';
 import { showError } from './notifications';
 
@@ -25,7 +26,7 @@ export const notesStore = writable<
 let notePollerHandle: ReturnType<typeof setInterval> | null;
 
 async function decryptNotes(
-  notes: EncryptedNote[],
+  notes: NoteListing[],
   cryptoService: CryptoService
 ): Promise<NoteModel[]> {
   // When notes are initially created, they do not have (and cannot have) any
@@ -38,7 +39,7 @@ async function decryptNotes(
   // already exists but doesn't have any (encrypted) content yet.
   // To avoid decryption errors for these notes, we skip deserializing (and thus
   // decrypting) these notes here.
-  const notes_with_content = notes.filter((note) => note.encrypted_text != "");
+  const notes_with_content = notes.filter((note) => noteText(note) != "");
 
   return await Promise.all(
     notes_with_content.map((encryptedNote) => deserialize(encryptedNote, cryptoService))
@@ -56,21 +57,50 @@ export async function refreshNotes(
   actor: BackendActor,
   cryptoService: CryptoService
 ) {
-  const encryptedNotes = await actor.get_notes();
+  const encryptedNotes = await fetchNotes(actor, true);
 
   const notes = await decryptNotes(encryptedNotes, cryptoService);
   updateNotes(notes);
 }
 
+/**
+ * Lists all notes of the caller, following the page cursors. With
+ * `includeContent`, content too large to be listed is read in chunks.
+ */
+export async function fetchNotes(
+  actor: BackendActor,
+  includeContent: boolean
+): Promise<NoteListing[]> {
+  const notes: NoteListing[] = [];
+  let cursor: [] | [string] = [];
+  do {
+    const page = unwrap(await actor.get_notes({ cursor, limit: [] }, includeContent));
+    notes.push(...page.items);
+    cursor = page.next_cursor;
+  } while (cursor.length > 0);
+
+  for (const note of notes) {
+    const [chunkCount] = note.omitted_content_chunks;
+    if (chunkCount !== undefined) {
+      const bytes: number[] = [];
+      for (let index = 0; index < chunkCount; index++) {
+        bytes.push(...unwrap(await actor.get_note_chunk(note.id, index)));
+      }
+      note.encrypted_text = [new TextDecoder().decode(new Uint8Array(bytes))];
+    }
+  }
+  return notes;
+}
+
 export async function addNote(
   note: NoteModel,
   actor: BackendActor,
   crypto: CryptoService
 ) {
-  const new_id: bigint = await actor.create_note();
+  const new_id = unwrap(await actor.create_note());
   note.id = new_id;
   const encryptedNote = (await serialize(note, crypto)).encrypted_text;
-  await actor.update_note(new_id, encryptedNote);
+  unwrap(await actor.update_note(new_id, encryptedNote));
 }
 export async function updateNote(
   note: NoteModel,
@@ -78,7 +108,7 @@ export async function updateNote(
   crypto: CryptoService
 ) {
   const encryptedNote = await serialize(note, crypto);
-  await actor.update_note(note.id, encryptedNote.encrypted_text);
+  unwrap(await actor.update_note(note.id, encryptedNote.encrypted_text));
 }
 
 export async function addUser(
@@ -86,7 +116,8 @@ export async function addUser(
   user: string,
   actor: BackendActor,
 ) {
-  await actor.add_user(id, user);
+  // Shares made from the UI keep the read and write access they always had.
+  unwrap(await actor.add_user(id, user, { Editor: null }, []));
 }
 
 export async function removeUser(
@@ -94,7 +125,7 @@ export async function removeUser(
   user: string,
   actor: BackendActor,
 ) {
-  await actor.remove_user(id, user);
+  unwrap(await actor.remove_user(id, user));
 }
 
 auth.subscribe(async ($auth) => {